@@ -0,0 +1,99 @@
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, List, ListItem, ListState, StatefulWidget};
+use std::collections::HashSet;
+
+use crate::collection::{Album, TrackStatus};
+use crate::progress::format_bytes;
+
+// A closer look at one album: the state of each track and the extras that came with it
+pub struct AlbumDetail {
+    pub album_id: u32,
+    track_state: ListState,
+    // Indexes of the tracks picked for download
+    marked: HashSet<usize>,
+}
+
+impl AlbumDetail {
+    pub fn new(album_id: u32) -> Self {
+        Self {
+            album_id,
+            track_state: ListState::default().with_selected(Some(0)),
+            marked: HashSet::new(),
+        }
+    }
+
+    pub fn toggle_mark(&mut self, album: &Album) {
+        if let Some(index) = self
+            .track_state
+            .selected()
+            .filter(|index| *index < album.tracks.len())
+            && !self.marked.remove(&index)
+        {
+            self.marked.insert(index);
+        }
+    }
+
+    // The marked tracks, or the selected one if none are marked
+    pub fn take_marked(&mut self, album: &Album) -> Vec<usize> {
+        let mut marked = self.marked.drain().collect::<Vec<usize>>();
+        if marked.is_empty() {
+            marked.extend(
+                self.track_state
+                    .selected()
+                    .filter(|index| *index < album.tracks.len()),
+            );
+        }
+        marked.sort();
+        marked
+    }
+
+    pub fn select_previous(&mut self) {
+        self.track_state.select_previous();
+    }
+
+    pub fn select_next(&mut self) {
+        self.track_state.select_next();
+    }
+
+    pub fn render(&mut self, album: &Album, area: Rect, buf: &mut Buffer) {
+        let tracks = album.tracks.iter().enumerate().map(|(index, track)| {
+            let status = match &track.status {
+                TrackStatus::Missing => "💾".to_owned(),
+                TrackStatus::Queued => "⏳".to_owned(),
+                TrackStatus::Downloading {
+                    received,
+                    total: Some(total),
+                } if *total > 0 => format!("⏳ {}%", received * 100 / total),
+                TrackStatus::Downloading { received, .. } => {
+                    format!("⏳ {}", format_bytes(*received as f64))
+                }
+                TrackStatus::Complete => "✅".to_owned(),
+                TrackStatus::Failed(failure) => {
+                    format!("🚨 {} ({} attempts)", failure.error, failure.attempts)
+                }
+                TrackStatus::Corrupt => "💔 corrupt".to_owned(),
+            };
+            let mark = match self.marked.contains(&index) {
+                true => "[x]",
+                false => "[ ]",
+            };
+            ListItem::new(format!(
+                "{mark} {:02} - {} {status}",
+                track.number, track.title
+            ))
+        });
+
+        let extras = album.extras.iter().map(|extra| {
+            let name = extra.file_name().unwrap_or_default().to_string_lossy();
+            ListItem::new(format!("📎 {name}"))
+        });
+
+        let list = List::new(tracks.chain(extras).collect::<Vec<ListItem>>())
+            .highlight_symbol(">")
+            .block(Block::bordered().title(album.title.clone()).title_bottom(
+                "'m' mark track | 'd' download marked | 'r' retry failed tracks | 'esc' close",
+            ));
+
+        StatefulWidget::render(list, area, buf, &mut self.track_state);
+    }
+}
@@ -1,8 +1,15 @@
-use crate::bandcamp::Item;
+use crate::album_detail::AlbumDetail;
+use crate::cache::{load_usage, save_usage};
 use crate::collection::{Album, Collection};
-use crate::download_manager::DownloadManager;
+use crate::config::Config;
+use crate::delete_confirmation::DeleteConfirmation;
+use crate::download_manager::{DownloadManager, load_queue, remove_partial_downloads};
 use crate::events::Event;
 use crate::player::Player;
+use crate::progress::{DownloadMeter, format_bytes, format_duration};
+use crate::source::{SourceAlbum, SourceTrack};
+use crate::stats::LibraryStats;
+use crate::watcher::LibraryWatcher;
 
 use anyhow::Result;
 use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
@@ -14,6 +21,8 @@ use rodio::OutputStream;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
 pub struct App {
@@ -23,27 +32,89 @@ pub struct App {
     // TODO: should the collection just have the download manager? Probably...
     download_manager: DownloadManager,
     player: Player,
+    // Shown instead of the player while open
+    album_detail: Option<AlbumDetail>,
+    // Shown instead of the player (or album detail) while open
+    delete_confirmation: Option<DeleteConfirmation>,
+    // Shown instead of the player while open, as of when it was opened. Some(None) while
+    // they're worked out, see Event::StatsComputed.
+    stats: Option<Option<LibraryStats>>,
+    download_meter: DownloadMeter,
+    config: Config,
+    // Where last played times and pins are saved
+    usage_path: PathBuf,
+    // None if the library can't be watched, the statuses are then as of startup
+    _watcher: Option<LibraryWatcher>,
     error: String,
 }
 
 impl App {
     pub fn new(
-        bandcamp_items: Vec<Item>,
+        mut collection: Collection,
         audio_output_stream: &OutputStream,
         download_runtime: Runtime,
+        config: Config,
+        queue_path: PathBuf,
+        usage_path: PathBuf,
     ) -> Self {
         let channel = mpsc::channel();
-        let collection = Collection::from_bandcamp_items(bandcamp_items);
-        let download_manager = DownloadManager::new(channel.0.clone(), download_runtime);
+        let mut error = match remove_partial_downloads(&collection) {
+            Ok(()) => "".to_owned(),
+            Err(err) => format!("{err:?}"),
+        };
+
+        let queued = load_queue(&queue_path);
+        let download_manager = DownloadManager::new(
+            channel.0.clone(),
+            download_runtime,
+            &config,
+            queue_path,
+            collection.library_root().to_owned(),
+        );
+        match queued {
+            Ok(queued) => collection.resume_downloads(queued, &download_manager),
+            Err(err) => error = format!("{err:?}"),
+        }
+        match load_usage(&usage_path) {
+            Ok(usage) => collection.apply_usage(usage),
+            Err(err) => error = format!("{err:?}"),
+        }
+        let watcher = LibraryWatcher::new(collection.library_root(), channel.0.clone())
+            .inspect_err(|err| error = format!("{err:?}"))
+            .ok();
         let player = Player::new(audio_output_stream);
 
-        App {
+        let mut app = App {
             exit: false,
             collection,
             download_manager,
             channel,
             player,
-            error: "".to_owned(),
+            album_detail: None,
+            delete_confirmation: None,
+            stats: None,
+            download_meter: DownloadMeter::default(),
+            config,
+            usage_path,
+            _watcher: watcher,
+            error,
+        };
+        app.evict_albums();
+        app
+    }
+
+    // Marks damaged tracks so they get downloaded again, see Collection::verify
+    pub fn verify_library(&mut self) {
+        match self.collection.verify() {
+            Ok(report) if report.damaged.is_empty() => (),
+            Ok(report) => {
+                self.error = format!(
+                    "{} of {} tracks on disk are damaged and need downloading again ('i' for details)",
+                    report.damaged.len(),
+                    report.checked
+                )
+            }
+            Err(err) => self.error = format!("{err:?}"),
         }
     }
 
@@ -51,23 +122,74 @@ impl App {
         self.channel.0.clone()
     }
 
-    pub fn handle_next_event(&mut self) -> Result<()> {
-        match self.channel.1.try_recv() {
-            Ok(event) => match event {
-                Event::Input(key_event) => self.on_key_event(key_event)?,
-                Event::AlbumDownloaded(id) => {
-                    if let Some(album) = self.collection.set_downloaded(id) {
-                        self.player.play_if_empty(album.into())?
-                    }
+    // Handles everything that came in since the last frame. With several downloads running,
+    // progress events come in faster than one a frame, and key presses would queue up behind them.
+    pub fn handle_events(&mut self) -> Result<()> {
+        while !self.exit {
+            match self.channel.1.try_recv() {
+                Ok(event) => self.handle_event(event)?,
+                // TODO: consider letting the player have its own thread that tries to play the next track when appropriate
+                Err(TryRecvError::Empty) => return self.player.try_play_next_track(),
+                Err(_) => self.exit = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Input(key_event) => self.on_key_event(key_event)?,
+            Event::AlbumDownloaded(id) => {
+                if let Some(album) = self.collection.set_downloaded(id) {
+                    self.player.play_if_empty(album.clone())?
                 }
-                Event::AlbumDownLoadFailed(id, err) => {
-                    self.error = format!("{err:?}");
-                    self.collection.set_failed(id);
+                self.on_album_on_disk(id);
+            }
+            Event::TrackDownloadProgress(id, index, received, total) => {
+                let new_bytes = self
+                    .collection
+                    .set_track_progress(id, index, received, total);
+                self.download_meter.record(new_bytes);
+            }
+            Event::TrackDownloaded(id, index) => {
+                if let Some(file_path) = self.collection.set_track_downloaded(id, index) {
+                    self.player.set_playable(&file_path);
                 }
-            },
-            // TODO: consider letting the player have its own thread that tries to play the next track when appropriate
-            Err(TryRecvError::Empty) => self.player.try_play_next_track()?,
-            Err(_) => self.exit = true,
+            }
+            Event::AlbumPackageDownloaded(id, extras) => {
+                if let Some(album) = self.collection.set_package_downloaded(id, extras) {
+                    self.player.play_if_empty(album.clone())?
+                }
+                self.on_album_on_disk(id);
+            }
+            Event::LibraryChanged(paths) => {
+                let (appeared, gone) = self.collection.refresh_paths(&paths);
+                appeared
+                    .iter()
+                    .for_each(|path| self.player.set_playable(path));
+                gone.iter().for_each(|path| self.player.set_missing(path));
+            }
+            // Unless the view was closed in the meantime
+            Event::StatsComputed(stats) => {
+                if let Some(pending @ None) = self.stats.as_mut() {
+                    *pending = Some(stats);
+                }
+            }
+            Event::Error(err) => self.error = format!("{err:?}"),
+            Event::AlbumDownLoadFailed(id, failures) => {
+                let failed = failures.len();
+                if let Some(album) = self.collection.set_failed(id, failures) {
+                    self.error = format!(
+                        "{failed} of {} tracks of {} failed to download ('i' for details)",
+                        album.tracks.len(),
+                        album.title
+                    );
+                }
+            }
+            Event::PackageDownloadFailed(id, err) => {
+                self.collection.set_package_failed(id, format!("{err:#}"));
+                self.error = format!("{err:?}");
+            }
         }
         Ok(())
     }
@@ -76,13 +198,24 @@ impl App {
         if key.kind != KeyEventKind::Press {
             return Ok(());
         }
+
+        if self.on_delete_confirmation_key_event(key)
+            || self.on_stats_key_event(key)
+            || self.on_album_detail_key_event(key)
+        {
+            return Ok(());
+        }
+
         match key.code {
             KeyCode::Enter => {
                 if let Some(album) = self
                     .collection
                     .download_selected_album(&self.download_manager)
                 {
-                    self.player.play(album.into())?
+                    let id = album.id;
+                    self.player.play(album.clone())?;
+                    self.collection.set_played(id);
+                    self.save_usage();
                 }
             }
             KeyCode::Up => {
@@ -99,19 +232,60 @@ impl App {
             }
             KeyCode::Char(' ') => self.player.toggle_playback(),
             KeyCode::Char('d') => self.collection.download_all(&self.download_manager),
+            KeyCode::Char('x') => self
+                .collection
+                .cancel_selected_download(&self.download_manager),
+            KeyCode::Char('X') => self.collection.cancel_all_downloads(&self.download_manager),
+            KeyCode::Char('p') => {
+                self.download_manager.toggle_pause();
+            }
+            KeyCode::Char('b') => {
+                if let Err(err) = self
+                    .collection
+                    .download_selected_package(&self.download_manager, &self.config.package_format)
+                {
+                    self.error = format!("{err:?}");
+                }
+            }
+            KeyCode::Char('k') => {
+                self.collection.toggle_pin_selected();
+                self.save_usage();
+            }
+            KeyCode::Char('s') => {
+                self.album_detail = None;
+                self.stats = Some(None);
+                let albums = self.collection.albums().cloned().collect::<Vec<Album>>();
+                let library_root = self.collection.library_root().to_owned();
+                let sender = self.clone_sender();
+                thread::spawn(move || {
+                    let stats = LibraryStats::of(&albums, &library_root);
+                    let _ = sender.send(Event::StatsComputed(stats));
+                });
+            }
+            KeyCode::Char('i') => {
+                self.stats = None;
+                self.album_detail = self
+                    .collection
+                    .selected_album()
+                    .map(|album| AlbumDetail::new(album.id));
+            }
+            KeyCode::Delete => self.confirm_delete(),
             KeyCode::Char('q') => self.exit = true,
             // 't' for test? As in, play test sound? I guess that's fine if we don't need t for anything else
             KeyCode::Char('t') => {
-                let album = crate::player::Album {
+                let album = SourceAlbum {
+                    artist: "Me".to_owned(),
                     title: "Test file".to_owned(),
-                    tracks: vec![crate::player::Track {
+                    tracks: vec![SourceTrack {
                         number: 1,
                         title: "file_example_MP3_2MG".to_owned(),
-                        file_path: PathBuf::from_str("./file_example_MP3_2MG.mp3")?,
+                        duration: None,
                     }],
-                    band_name: "Me".to_owned(),
+                    ..SourceAlbum::default()
                 };
-                self.player.play(album)?;
+                let file_path = PathBuf::from_str("./file_example_MP3_2MG.mp3")?;
+                self.player
+                    .play(Album::new(album, vec![file_path], vec![None]))?;
             }
             _ => (),
         };
@@ -120,12 +294,185 @@ impl App {
     }
 }
 
+impl App {
+    // Returns whether the key was meant for the delete confirmation
+    fn on_delete_confirmation_key_event(&mut self, key: KeyEvent) -> bool {
+        let Some(confirmation) = self.delete_confirmation.take() else {
+            return false;
+        };
+
+        match key.code {
+            KeyCode::Char('y') => self.delete_album(confirmation.album_id),
+            KeyCode::Char('n') | KeyCode::Esc => (),
+            // Anything else leaves the question open
+            _ => self.delete_confirmation = Some(confirmation),
+        }
+        true
+    }
+
+    // Asks before deleting the selected album, see delete_album
+    fn confirm_delete(&mut self) {
+        let Some(album) = self.collection.selected_album() else {
+            return;
+        };
+
+        if !album.downloadable {
+            self.error = format!(
+                "{} isn't downloaded from anywhere, so it's left alone",
+                album.title
+            );
+        } else if is_in_player(&self.player, album) {
+            self.error = format!(
+                "{} is in the player, play something else first",
+                album.title
+            );
+        } else if album.files_on_disk().next().is_none() {
+            self.error = format!("{} has nothing on disk", album.title);
+        } else {
+            self.delete_confirmation = Some(DeleteConfirmation::new(album));
+        }
+    }
+
+    // The album in the player stays, whatever else happened since the question was asked
+    fn delete_album(&mut self, id: u32) {
+        let Some(album) = self.collection.album(id) else {
+            return;
+        };
+        if is_in_player(&self.player, album) {
+            self.error = format!(
+                "{} is in the player, play something else first",
+                album.title
+            );
+            return;
+        }
+
+        let title = album.title.clone();
+        match self.collection.delete_album(id) {
+            Ok(()) => self.error = format!("Deleted {title}"),
+            Err(err) => self.error = format!("{err:?}"),
+        }
+    }
+
+    // Returns whether the key was meant for the statistics view
+    fn on_stats_key_event(&mut self, key: KeyEvent) -> bool {
+        match (&self.stats, key.code) {
+            (Some(_), KeyCode::Esc | KeyCode::Char('s')) => self.stats = None,
+            _ => return false,
+        }
+        true
+    }
+
+    // Returns whether the key was meant for the album detail view
+    fn on_album_detail_key_event(&mut self, key: KeyEvent) -> bool {
+        let Some(detail) = self.album_detail.as_mut() else {
+            return false;
+        };
+
+        match key.code {
+            KeyCode::Up => detail.select_previous(),
+            KeyCode::Down => detail.select_next(),
+            KeyCode::Esc | KeyCode::Char('i') => self.album_detail = None,
+            KeyCode::Char('r') => self
+                .collection
+                .retry_failed(detail.album_id, &self.download_manager),
+            KeyCode::Char('m') => {
+                if let Some(album) = self.collection.album(detail.album_id) {
+                    detail.toggle_mark(album);
+                }
+            }
+            KeyCode::Char('d') => {
+                if let Some(album) = self.collection.album(detail.album_id) {
+                    let marked = detail.take_marked(album);
+                    self.collection.download_tracks(
+                        detail.album_id,
+                        marked,
+                        &self.download_manager,
+                    );
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // A fresh download counts as played, or it would be the first thing to be evicted
+    fn on_album_on_disk(&mut self, id: u32) {
+        self.collection.set_played(id);
+        self.save_usage();
+        self.evict_albums();
+    }
+
+    fn save_usage(&mut self) {
+        if let Err(err) = save_usage(&self.collection.usage(), &self.usage_path) {
+            self.error = format!("{err:?}");
+        }
+    }
+
+    // In cache mode, keeps the library under the cache size by deleting the albums that haven't
+    // been played for longest. The album in the player stays.
+    fn evict_albums(&mut self) {
+        let Some(cache_size) = self.config.cache_size else {
+            return;
+        };
+
+        let player = &self.player;
+        let evicted = self
+            .collection
+            .evict(cache_size, |album| is_in_player(player, album));
+        match evicted {
+            Ok(evicted) if !evicted.is_empty() => {
+                self.error = format!(
+                    "Removed {} to stay under {}",
+                    evicted.join(", "),
+                    format_bytes(cache_size as f64)
+                )
+            }
+            Ok(_) => (),
+            Err(err) => self.error = format!("{err:?}"),
+        }
+    }
+
+    // e.g. "⬇ 3 albums | 1.2 MB/s | ETA 4m 10s"
+    fn download_summary(&mut self) -> String {
+        let (albums, remaining_bytes) = self
+            .collection
+            .downloading()
+            .fold((0, 0), |(albums, bytes), progress| {
+                (albums + 1, bytes + progress.remaining_bytes())
+            });
+
+        if albums == 0 {
+            return String::new();
+        }
+
+        if self.download_manager.is_paused() {
+            return format!("⏸ {albums} albums paused ('p' to resume)");
+        }
+
+        let waiting = match self.download_manager.is_waiting_for_window() {
+            true => " | 🌙 bulk downloads wait for their download window",
+            false => "",
+        };
+
+        let bytes_per_second = self.download_meter.bytes_per_second();
+        let eta = match bytes_per_second {
+            0.0 => "?".to_owned(),
+            speed => format_duration(Duration::from_secs_f64(remaining_bytes as f64 / speed)),
+        };
+
+        format!(
+            "⬇ {albums} albums | {}/s | ETA {eta}{waiting}",
+            format_bytes(bytes_per_second)
+        )
+    }
+}
+
 impl Widget for &mut App {
     fn render(self, area: Rect, buf: &mut Buffer)
     where
         Self: Sized,
     {
-        let [header, body, footer, error] = Layout::default()
+        let [header, body, downloads, footer, error] = Layout::default()
             .direction(Direction::Vertical)
             .margin(1)
             .constraints(vec![
@@ -133,6 +480,7 @@ impl Widget for &mut App {
                 Constraint::Fill(1),
                 Constraint::Length(1),
                 Constraint::Length(1),
+                Constraint::Length(1),
             ])
             .areas(area);
 
@@ -147,10 +495,28 @@ impl Widget for &mut App {
 
         Widget::render(&mut self.collection, left, buf);
 
-        Widget::render(&mut self.player, right, buf);
+        match (
+            &self.delete_confirmation,
+            &self.stats,
+            &mut self.album_detail,
+            &self.collection,
+        ) {
+            (Some(confirmation), _, _, _) => confirmation.render(right, buf),
+            (None, Some(Some(stats)), _, _) => stats.render(right, buf),
+            (None, Some(None), _, _) => LibraryStats::render_pending(right, buf),
+            (None, None, Some(detail), collection) => match collection.album(detail.album_id) {
+                Some(album) => detail.render(album, right, buf),
+                None => Widget::render(&mut self.player, right, buf),
+            },
+            (None, None, None, _) => Widget::render(&mut self.player, right, buf),
+        }
+
+        Line::from(self.download_summary())
+            .alignment(Alignment::Center)
+            .render(downloads, buf);
 
         Line::from(
-            "'↑/↓' select album | 'enter' play album | 'spacebar' play/pause | '←/→' previous/next track | 'q' quit",
+            "'↑/↓' select album | 'enter' play album | 'b' download full package | 'x/X' cancel download/all | 'p' pause downloads | 'k' keep (pin) album | 'del' delete album | 'i' album details | 's' statistics | 'spacebar' play/pause | '←/→' previous/next track | 'q' quit",
         )
         .alignment(Alignment::Center)
         .render(footer, buf);
@@ -159,20 +525,9 @@ impl Widget for &mut App {
     }
 }
 
-impl From<&Album> for crate::player::Album {
-    fn from(value: &Album) -> Self {
-        Self {
-            title: value.title.clone(),
-            tracks: value
-                .tracks
-                .iter()
-                .map(|track| crate::player::Track {
-                    number: track.number.clone(),
-                    title: track.title.clone(),
-                    file_path: track.file_path.clone(),
-                })
-                .collect(),
-            band_name: value.band_name.clone(),
-        }
-    }
+fn is_in_player(player: &Player, album: &Album) -> bool {
+    album
+        .tracks
+        .iter()
+        .any(|track| player.is_loaded(&track.file_path))
 }
@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use reqwest::{
     StatusCode,
     blocking::{Body, Client},
@@ -7,9 +7,19 @@ use reqwest::{
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::download_manager::in_any_audio_format;
+use crate::json_l::{read_lines_from_file, replace_file_lines};
+use crate::library::{LibraryLayout, TrackValues, clashes};
+use crate::source::{MusicSource, SourceAlbum, SourceTrack, year_of};
+
 static X_BANDCAMP_DM: &str = "X-Bandcamp-Dm";
+const COLLECTION_PAGE_SIZE: usize = 5;
 
 pub struct BandCampClient {
     client: Client,
@@ -184,7 +194,7 @@ pub struct CollectionResponse {
     pub items: Vec<Item>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Item {
     pub tralbum_type: String,
     pub tralbum_id: u32,
@@ -193,6 +203,17 @@ pub struct Item {
     pub tracks: Vec<Track>,
     pub band_info: BandInfo,
     pub token: String,
+    // Only present for purchases, and not in collections cached before it was added
+    #[serde(default)]
+    pub redownload_url: Option<String>,
+    // Not in collections cached before it was added
+    #[serde(default)]
+    pub release_date: Option<String>,
+    // Not in collections cached before these were added
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub art_id: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -201,9 +222,12 @@ pub struct Track {
     pub title: String,
     pub hq_audio_url: String,
     pub track_number: u8,
+    // In seconds. Not in collections cached before it was added
+    #[serde(default)]
+    pub duration: Option<f64>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BandInfo {
     pub band_id: u32,
     pub name: String,
@@ -211,6 +235,293 @@ pub struct BandInfo {
     pub page_url: String,
 }
 
+// The download page embeds its data as HTML-escaped JSON in the data-blob attribute:
+// <div id="pagedata" data-blob="{&quot;digital_items&quot;:[{&quot;downloads&quot;:{&quot;flac&quot;:{&quot;url&quot;:...}}}]}">
+pub fn parse_package_url(download_page: &str, format: &str) -> Result<String> {
+    let blob = download_page
+        .split_once("data-blob=\"")
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(blob, _)| unescape_html(blob))
+        .ok_or(anyhow!("Download page has no data blob"))?;
+
+    let page_data = serde_json::from_str::<DownloadPageData>(&blob)?;
+
+    page_data
+        .digital_items
+        .into_iter()
+        .find_map(|mut item| item.downloads.remove(format))
+        .map(|download| download.url)
+        .ok_or(anyhow!("Format {format} is not available for download"))
+}
+
+fn unescape_html(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[derive(Debug, Deserialize)]
+struct DownloadPageData {
+    digital_items: Vec<DigitalItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalItem {
+    downloads: HashMap<String, Download>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Download {
+    url: String,
+}
+
+// The albums bought on Bandcamp. They're cached in a JSON lines file between syncs,
+// since syncing means logging in.
+pub struct BandcampSource {
+    items: Vec<Item>,
+    cache_path: PathBuf,
+    layout: LibraryLayout,
+    // What goes after album titles in paths, see album_suffixes
+    album_suffixes: HashMap<u32, String>,
+}
+
+impl BandcampSource {
+    // Starts out with the cached collection, or nothing if it was never synced
+    pub fn load(cache_path: PathBuf, layout: LibraryLayout) -> Result<Self> {
+        let items = match File::open(&cache_path) {
+            Ok(file) => read_lines_from_file(file)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self::new(items, cache_path, layout))
+    }
+
+    fn new(items: Vec<Item>, cache_path: PathBuf, layout: LibraryLayout) -> Self {
+        let album_suffixes = items
+            .iter()
+            .map(|item| item.tralbum_id)
+            .zip(album_suffixes(&items, &layout))
+            .collect();
+        Self {
+            items,
+            cache_path,
+            layout,
+            album_suffixes,
+        }
+    }
+
+    pub fn is_cached(&self) -> bool {
+        self.cache_path.exists()
+    }
+
+    // The same albums, laid out another way (see relocate)
+    pub fn with_layout(&self, layout: LibraryLayout) -> Self {
+        Self::new(self.items.clone(), self.cache_path.clone(), layout)
+    }
+
+    fn item(&self, album_id: u32) -> Option<&Item> {
+        self.items.iter().find(|item| item.tralbum_id == album_id)
+    }
+}
+
+impl MusicSource for BandcampSource {
+    fn albums(&self) -> Vec<SourceAlbum> {
+        self.items
+            .iter()
+            .map(|item| SourceAlbum {
+                id: item.tralbum_id,
+                artist: item.band_info.name.clone(),
+                title: item.title.clone(),
+                year: item
+                    .release_date
+                    .as_deref()
+                    .and_then(year_of)
+                    .map(str::to_owned),
+                label: item.label.clone(),
+                artwork_url: item.art_id.map(artwork_url),
+                package_url: item.redownload_url.clone(),
+                tracks: item
+                    .tracks
+                    .iter()
+                    .map(|track| SourceTrack {
+                        number: track.track_number,
+                        title: track.title.clone(),
+                        duration: track.duration.map(Duration::from_secs_f64),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // Tracks go where the library layout says, unless they were downloaded before names were sanitised
+    fn track_locations(&self, album: &SourceAlbum) -> Vec<PathBuf> {
+        let Some(item) = self.item(album.id) else {
+            return Vec::new();
+        };
+        let album_suffix = self
+            .album_suffixes
+            .get(&item.tralbum_id)
+            .map_or("", String::as_str);
+        let track_path = |track: &Track, title_suffix: &str| {
+            self.layout.track_path(&TrackValues {
+                artist: &item.band_info.name,
+                album: &item.title,
+                album_suffix,
+                year: album.year.as_deref(),
+                track: track.track_number,
+                title: &track.title,
+                title_suffix,
+                ext: "mp3",
+            })
+        };
+
+        let mut file_paths = item
+            .tracks
+            .iter()
+            .map(|track| track_path(track, ""))
+            .collect::<Vec<PathBuf>>();
+        let names = item
+            .tracks
+            .iter()
+            .zip(&file_paths)
+            .map(|(track, path)| (track.track_id, path.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>();
+        for ((track, clashes), file_path) in
+            item.tracks.iter().zip(clashes(&names)).zip(&mut file_paths)
+        {
+            if clashes {
+                *file_path = track_path(track, &format!(" [{}]", track.track_id));
+            }
+        }
+
+        // Albums downloaded before names were sanitised stay where they are
+        let legacy_dir = self
+            .layout
+            .root
+            .join(legacy_name(&item.band_info.name))
+            .join(legacy_name(&item.title));
+        if self.layout.is_default() && legacy_dir.is_dir() {
+            for (track, file_path) in item.tracks.iter().zip(&mut file_paths) {
+                *file_path = to_legacy_file_path(&legacy_dir, track, file_path);
+            }
+        }
+
+        // Tracks imported in another format (FLAC from a download page, ...) are found by their name
+        for file_path in &mut file_paths {
+            if let Some(on_disk) = in_any_audio_format(file_path) {
+                *file_path = on_disk;
+            }
+        }
+
+        file_paths
+    }
+
+    fn download_url(&self, album: &SourceAlbum, index: usize) -> Option<String> {
+        let track = self.item(album.id)?.tracks.get(index)?;
+        Some(track.hq_audio_url.clone())
+    }
+
+    // Logs in (see log_in) and caches the whole collection
+    fn sync(&mut self) -> Result<()> {
+        let client = log_in()?;
+
+        // On stderr, so it doesn't get mixed up with the JSON output of the sync command
+        eprint!("Caching collection... ");
+        std::io::stderr().flush()?;
+        let items = client.get_entire_collection(COLLECTION_PAGE_SIZE)?;
+        eprintln!("Done!");
+
+        replace_file_lines(&self.cache_path, items.iter())?;
+        *self = Self::new(items, self.cache_path.clone(), self.layout.clone());
+        Ok(())
+    }
+}
+
+// Uses BANDCAMP_USERNAME and BANDCAMP_PASSWORD when they're set, since unattended runs
+// (e.g. sync from cron) can't type in a password. Asks for them otherwise.
+fn log_in() -> Result<BandCampClient> {
+    if let (Ok(username), Ok(password)) = (
+        std::env::var("BANDCAMP_USERNAME"),
+        std::env::var("BANDCAMP_PASSWORD"),
+    ) {
+        return BandCampClient::new(&username, &password);
+    }
+
+    loop {
+        println!("Bandcamp username:");
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        let username = input.trim_end().to_owned();
+
+        println!("Password:");
+        let password = rpassword::read_password()?;
+
+        match BandCampClient::new(&username, &password) {
+            Ok(client) => return Ok(client),
+            Err(err) => println!("{err:?}"),
+        }
+    }
+}
+
+// What goes after album titles in paths. Albums that would end up in the same directory get
+// their id added, see library::clashes.
+fn album_suffixes(items: &[Item], layout: &LibraryLayout) -> Vec<String> {
+    let directories = items
+        .iter()
+        .map(|item| {
+            let values = TrackValues {
+                artist: &item.band_info.name,
+                album: &item.title,
+                album_suffix: "",
+                year: item.release_date.as_deref().and_then(year_of),
+                track: 1,
+                title: "",
+                title_suffix: "",
+                ext: "mp3",
+            };
+            let directory = layout.track_path(&values);
+            let directory = directory.parent().unwrap_or(&layout.root);
+            (item.tralbum_id, directory.to_string_lossy().into_owned())
+        })
+        .collect::<Vec<_>>();
+
+    items
+        .iter()
+        .zip(clashes(&directories))
+        .map(|(item, clashes)| match clashes {
+            true => format!(" [{}]", item.tralbum_id),
+            false => String::new(),
+        })
+        .collect()
+}
+
+// Bandcamp's 700x700 version of the cover art
+fn artwork_url(art_id: u64) -> String {
+    format!("https://f4.bcbits.com/img/a{art_id:010}_16.jpg")
+}
+
+// Tracks downloaded before names were sanitised keep the name they were saved under.
+// Anything new in the album goes next to them.
+fn to_legacy_file_path(legacy_dir: &Path, track: &Track, file_path: &Path) -> PathBuf {
+    let legacy = legacy_dir.join(format!(
+        "{:02} - {}.mp3",
+        track.track_number,
+        legacy_name(&track.title)
+    ));
+    match legacy.exists() {
+        true => legacy,
+        false => legacy_dir.join(file_path.file_name().unwrap_or_default()),
+    }
+}
+
+// Names used to only have '/' taken out, which left everything else that trips up other file systems
+fn legacy_name(name: &str) -> String {
+    name.replace('/', "")
+}
+
 mod crypto {
     use hmac::{Hmac, Mac};
     use sha1::Sha1;
@@ -244,3 +555,18 @@ mod crypto {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_package_url_finds_requested_format() {
+        let page = r#"<div id="pagedata" data-blob="{&quot;digital_items&quot;:[{&quot;downloads&quot;:{&quot;flac&quot;:{&quot;url&quot;:&quot;https://p4.bcbits.com/download/album/flac?id=1&amp;sig=2&quot;}}}]}"></div>"#;
+
+        let url = parse_package_url(page, "flac").unwrap();
+
+        assert_eq!(url, "https://p4.bcbits.com/download/album/flac?id=1&sig=2");
+        assert!(parse_package_url(page, "mp3-320").is_err());
+    }
+}
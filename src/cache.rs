@@ -0,0 +1,87 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::json_l::{read_lines_from_file, replace_file_lines};
+
+// What's remembered about an album between runs, to decide what to evict when the library is too big
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlbumUsage {
+    pub album_id: u32,
+    pub last_played: Option<DateTime<Utc>>,
+    // Pinned albums are never evicted
+    pub pinned: bool,
+}
+
+pub fn load_usage(path: &Path) -> Result<Vec<AlbumUsage>> {
+    match File::open(path) {
+        Ok(file) => read_lines_from_file(file),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn save_usage(usage: &[AlbumUsage], path: &Path) -> Result<()> {
+    replace_file_lines(path, usage.iter())
+}
+
+// An album that could be evicted
+pub struct Candidate {
+    pub album_id: u32,
+    pub size: u64,
+    pub last_played: Option<DateTime<Utc>>,
+}
+
+// Picks the least recently played candidates (never played first) until the rest of the
+// library fits in cache_size
+pub fn pick_evictions(
+    mut candidates: Vec<Candidate>,
+    library_size: u64,
+    cache_size: u64,
+) -> Vec<u32> {
+    candidates.sort_by_key(|candidate| candidate.last_played);
+
+    let mut size = library_size;
+    candidates
+        .into_iter()
+        .take_while(|candidate| {
+            let over = size > cache_size;
+            size = size.saturating_sub(candidate.size);
+            over
+        })
+        .map(|candidate| candidate.album_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn least_recently_played_albums_go_first() {
+        let played = |day| Some(Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap());
+        let candidates = vec![
+            Candidate {
+                album_id: 1,
+                size: 10,
+                last_played: played(3),
+            },
+            Candidate {
+                album_id: 2,
+                size: 10,
+                last_played: played(1),
+            },
+            Candidate {
+                album_id: 3,
+                size: 10,
+                last_played: played(2),
+            },
+        ];
+
+        assert_eq!(pick_evictions(candidates, 50, 35), vec![2, 3]);
+    }
+}
@@ -1,28 +1,81 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
 use ratatui::prelude::*;
 use ratatui::widgets::{Block, List, ListState, StatefulWidget, Widget};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::bandcamp;
-use crate::download_manager::DownloadManager;
+use crate::cache::{AlbumUsage, Candidate, pick_evictions};
+use crate::download_manager::{
+    AUDIO_EXTENSIONS, DownloadManager, PART_EXTENSION, in_any_audio_format, is_same_track,
+    part_path,
+};
+use crate::download_queue::{QueuedAlbum, TrackDownload, TrackFailure, TrackState};
+use crate::progress::DownloadProgress;
+use crate::source::{MusicSource, SourceAlbum};
+use crate::storage::{lowercase_extension, remove_empty_dirs};
+use crate::tags::{AlbumTags, TrackTags};
+use crate::verify::{DamagedTrack, MANIFEST_FILE_NAME, Manifest, VerifyReport};
 
 pub struct Collection {
     albums: Vec<Album>,
     pub album_state: ListState,
+    library_root: PathBuf,
 }
 
 impl Collection {
-    pub fn from_bandcamp_items(bandcamp_items: Vec<crate::bandcamp::Item>) -> Self {
+    // Albums that more than one source has (the same artist and title, whatever the case) are
+    // taken from the first of them. Albums of the same source are all kept, even when they share
+    // an artist and title. library_root is where downloaded albums go.
+    pub fn from_sources(sources: &[&dyn MusicSource], library_root: PathBuf) -> Self {
         let mut album_state = ListState::default();
         album_state.select(Some(0));
 
-        let albums = bandcamp_items.into_iter().map(Album::from).collect();
+        let key = |album: &SourceAlbum| (album.artist.to_lowercase(), album.title.to_lowercase());
+        let mut known = HashSet::new();
+        let mut ids = HashSet::new();
+        let mut albums = Vec::new();
+        for source in sources {
+            let mut keys = HashSet::new();
+            for album in source.albums() {
+                if known.contains(&key(&album)) || !ids.insert(album.id) {
+                    continue;
+                }
+                keys.insert(key(&album));
+                let file_paths = source.track_locations(&album);
+                let download_urls = (0..album.tracks.len())
+                    .map(|index| source.download_url(&album, index))
+                    .collect();
+                albums.push(Album::new(album, file_paths, download_urls));
+            }
+            known.extend(keys);
+        }
+
+        // Same as extras_of, without going through every album for every album
+        let mut directories = HashMap::<PathBuf, usize>::new();
+        for directory in albums.iter().filter_map(Album::directory) {
+            *directories.entry(directory).or_default() += 1;
+        }
+        for album in &mut albums {
+            if let Some(directory) = album.directory()
+                && directories[&directory] == 1
+            {
+                album.extras = find_extras(directory);
+            }
+        }
 
         Self {
             albums,
             album_state,
+            library_root,
         }
     }
 
+    pub fn library_root(&self) -> &Path {
+        &self.library_root
+    }
+
     pub fn download_selected_album(
         &mut self,
         download_manager: &DownloadManager,
@@ -30,33 +83,483 @@ impl Collection {
         self.album_state
             .selected()
             .and_then(|index| self.albums.get_mut(index))
-            .take_if(|a| a.download(download_manager))
+            .take_if(|a| a.download(download_manager, true))
             .map(|a| &*a)
             .or(None)
     }
 
+    pub fn selected_album(&self) -> Option<&Album> {
+        self.album_state
+            .selected()
+            .and_then(|index| self.albums.get(index))
+    }
+
+    pub fn albums(&self) -> impl Iterator<Item = &Album> {
+        self.albums.iter()
+    }
+
+    pub fn album(&self, id: u32) -> Option<&Album> {
+        self.albums.iter().find(|album| album.id == id)
+    }
+
+    pub fn download_selected_package(
+        &mut self,
+        download_manager: &DownloadManager,
+        format: &str,
+    ) -> Result<()> {
+        let Some(album) = self
+            .album_state
+            .selected()
+            .and_then(|index| self.albums.get_mut(index))
+        else {
+            return Ok(());
+        };
+
+        if album.download_status() == DownloadStatus::Downloading {
+            return Ok(());
+        }
+
+        let redownload_url = album
+            .redownload_url
+            .clone()
+            .ok_or(anyhow!("No package download available for {}", album.title))?;
+        let album_dir = album
+            .directory()
+            .ok_or(anyhow!("{} has no tracks", album.title))?;
+
+        album
+            .tracks
+            .iter_mut()
+            .filter(|track| track.status != TrackStatus::Complete)
+            .for_each(|track| track.status = TrackStatus::Queued);
+
+        let tracks = album
+            .tracks
+            .iter()
+            .map(|track| (track.number, track.file_path.clone()))
+            .collect();
+        let tags = (0..album.tracks.len())
+            .map(|index| album.track_tags(index))
+            .collect();
+
+        download_manager.download_package(
+            album.id,
+            redownload_url,
+            format.to_owned(),
+            album_dir,
+            tracks,
+            tags,
+        );
+
+        Ok(())
+    }
+
     pub fn download_all(&mut self, download_manager: &DownloadManager) {
-        self.albums.iter_mut().for_each(|album| {
-            album.download(download_manager);
-        });
+        self.download_matching(download_manager, |_| true);
     }
 
-    pub fn set_downloaded(&mut self, id: u32) -> Option<&Album> {
+    pub fn download_matching(
+        &mut self,
+        download_manager: &DownloadManager,
+        matches: impl Fn(&Album) -> bool,
+    ) {
+        self.albums
+            .iter_mut()
+            .filter(|album| matches(album))
+            .for_each(|album| {
+                album.download(download_manager, false);
+            });
+    }
+
+    // Picks up the downloads that were still queued when the app last exited
+    pub fn resume_downloads(
+        &mut self,
+        queued: Vec<QueuedAlbum>,
+        download_manager: &DownloadManager,
+    ) {
+        for queued_album in queued {
+            let Some(album) = self
+                .albums
+                .iter_mut()
+                .find(|album| album.id == queued_album.album_id)
+            else {
+                continue;
+            };
+
+            let to_download = queued_album
+                .tracks
+                .iter()
+                .filter(|track| {
+                    track.state != TrackState::Downloaded
+                        || album
+                            .tracks
+                            .get(track.index)
+                            .is_none_or(|t| !t.file_path.exists())
+                })
+                .map(|track| track.index)
+                .collect();
+
+            album.download_tracks(to_download, download_manager, queued_album.priority);
+        }
+    }
+
+    // Downloads the tracks (by index) of the album that aren't on disk yet
+    pub fn download_tracks(
+        &mut self,
+        id: u32,
+        indexes: Vec<usize>,
+        download_manager: &DownloadManager,
+    ) {
         if let Some(album) = self.albums.iter_mut().find(|album| album.id == id) {
-            album.download_status = DownloadStatus::Downloaded;
-            Some(album)
-        } else {
-            None
+            let missing = indexes
+                .into_iter()
+                .filter(|index| {
+                    album.tracks.get(*index).is_some_and(|track| {
+                        matches!(
+                            track.status,
+                            TrackStatus::Missing | TrackStatus::Failed(_) | TrackStatus::Corrupt
+                        )
+                    })
+                })
+                .collect();
+            album.download_tracks(missing, download_manager, true);
+        }
+    }
+
+    // Downloads just the tracks that failed last time
+    pub fn retry_failed(&mut self, id: u32, download_manager: &DownloadManager) {
+        if let Some(album) = self
+            .albums
+            .iter_mut()
+            .find(|album| album.id == id)
+            .filter(|album| album.download_status() == DownloadStatus::DownloadFailed)
+        {
+            let failed = album.track_indexes(|status| matches!(status, TrackStatus::Failed(_)));
+            album.download_tracks(failed, download_manager, true);
+        }
+    }
+
+    pub fn cancel_selected_download(&mut self, download_manager: &DownloadManager) {
+        if let Some(album) = self
+            .album_state
+            .selected()
+            .and_then(|index| self.albums.get_mut(index))
+            .filter(|album| album.download_status() == DownloadStatus::Downloading)
+        {
+            download_manager.cancel(album.id);
+            album.refresh_in_progress_tracks();
         }
     }
 
-    pub fn set_failed(&mut self, id: u32) -> Option<&Album> {
+    pub fn cancel_all_downloads(&mut self, download_manager: &DownloadManager) {
+        download_manager.cancel_all();
+        self.albums
+            .iter_mut()
+            .for_each(Album::refresh_in_progress_tracks);
+    }
+
+    pub fn set_downloaded(&mut self, id: u32) -> Option<&Album> {
+        // Picks up the cover saved while tagging
+        let extras = self.album(id).map(|album| self.extras_of(album))?;
+        let album = self.albums.iter_mut().find(|album| album.id == id)?;
+        album.refresh_in_progress_tracks();
+        album.extras = extras;
+        Some(album)
+    }
+
+    // What else is in the album's directory, unless the directory has other albums' tracks in it
+    // too (a path template without a folder per album), so its files aren't only this album's
+    fn extras_of(&self, album: &Album) -> Vec<PathBuf> {
+        let Some(directory) = album.directory() else {
+            return Vec::new();
+        };
+        let shared = self
+            .albums
+            .iter()
+            .any(|other| other.id != album.id && other.directory().as_ref() == Some(&directory));
+        match shared {
+            true => Vec::new(),
+            false => find_extras(directory),
+        }
+    }
+
+    pub fn set_package_downloaded(&mut self, id: u32, extras: Vec<PathBuf>) -> Option<&Album> {
+        let album = self.albums.iter_mut().find(|album| album.id == id)?;
+        album.refresh_in_progress_tracks();
+        album.extras = extras;
+        Some(album)
+    }
+
+    pub fn set_failed(&mut self, id: u32, failures: Vec<TrackFailure>) -> Option<&Album> {
+        let album = self.albums.iter_mut().find(|album| album.id == id)?;
+        for failure in failures {
+            if let Some(track) = album.tracks.get_mut(failure.index) {
+                track.status = TrackStatus::Failed(failure);
+            }
+        }
+        album.refresh_in_progress_tracks();
+        Some(album)
+    }
+
+    // Package downloads fail as a whole, so every track that was waiting on it failed
+    pub fn set_package_failed(&mut self, id: u32, error: String) -> Option<&Album> {
+        let album = self.albums.iter_mut().find(|album| album.id == id)?;
+        for (index, track) in album.tracks.iter_mut().enumerate() {
+            if track.status == TrackStatus::Queued {
+                track.status = TrackStatus::Failed(TrackFailure {
+                    index,
+                    error: error.clone(),
+                    attempts: 1,
+                });
+            }
+        }
+        Some(album)
+    }
+
+    // Returns the number of newly received bytes
+    pub fn set_track_progress(
+        &mut self,
+        id: u32,
+        track_index: usize,
+        received: u64,
+        total: Option<u64>,
+    ) -> u64 {
+        let Some(track) = self.track_mut(id, track_index) else {
+            return 0;
+        };
+
+        let previous = match track.status {
+            TrackStatus::Queued => 0,
+            TrackStatus::Downloading { received, .. } => received,
+            // Progress that arrives after a cancel doesn't count
+            _ => return 0,
+        };
+        track.status = TrackStatus::Downloading { received, total };
+        received.saturating_sub(previous)
+    }
+
+    // Returns the track's file, which is now ready to play
+    pub fn set_track_downloaded(&mut self, id: u32, track_index: usize) -> Option<PathBuf> {
+        let track = self.track_mut(id, track_index)?;
+        track.status = TrackStatus::Complete;
+        Some(track.file_path.clone())
+    }
+
+    // Catches up with changes made outside the app (see LibraryWatcher) to the tracks at or under
+    // the paths, in any format (a FLAC copied in for an MP3, ...). Tracks being downloaded are left
+    // to the download manager. Returns the files of tracks that are now on disk, and of the ones
+    // that are gone.
+    pub fn refresh_paths(&mut self, paths: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut appeared = Vec::new();
+        let mut gone = Vec::new();
+        for track in self
+            .albums
+            .iter_mut()
+            .flat_map(|album| album.tracks.iter_mut())
+            .filter(|track| {
+                paths.iter().any(|path| {
+                    track.file_path.starts_with(path) || is_same_track(path, &track.file_path)
+                })
+            })
+        {
+            match (&track.status, in_any_audio_format(&track.file_path)) {
+                (TrackStatus::Missing | TrackStatus::Failed(_), Some(on_disk)) => {
+                    track.file_path = on_disk;
+                    track.status = TrackStatus::Complete;
+                    appeared.push(track.file_path.clone());
+                }
+                // Replaced with another format
+                (TrackStatus::Complete | TrackStatus::Corrupt, Some(on_disk))
+                    if on_disk != track.file_path =>
+                {
+                    track.file_path = on_disk;
+                    track.status = TrackStatus::Complete;
+                    appeared.push(track.file_path.clone());
+                }
+                (TrackStatus::Complete | TrackStatus::Corrupt, None) => {
+                    track.status = TrackStatus::Missing;
+                    gone.push(track.file_path.clone());
+                }
+                _ => (),
+            }
+        }
+        (appeared, gone)
+    }
+
+    fn track_mut(&mut self, id: u32, track_index: usize) -> Option<&mut Track> {
+        self.albums
+            .iter_mut()
+            .find(|album| album.id == id)?
+            .tracks
+            .get_mut(track_index)
+    }
+
+    // The .part files that belong to tracks which still need downloading
+    pub fn resumable_downloads(&self) -> HashSet<PathBuf> {
+        self.albums
+            .iter()
+            .flat_map(|album| album.tracks.iter())
+            .filter(|track| track.status != TrackStatus::Complete)
+            .map(|track| part_path(&track.file_path))
+            .collect()
+    }
+
+    pub fn apply_usage(&mut self, usage: Vec<AlbumUsage>) {
+        for usage in usage {
+            if let Some(album) = self.albums.iter_mut().find(|a| a.id == usage.album_id) {
+                album.last_played = usage.last_played;
+                album.pinned = usage.pinned;
+            }
+        }
+    }
+
+    // Only albums that have been played or pinned are worth saving
+    pub fn usage(&self) -> Vec<AlbumUsage> {
+        self.albums
+            .iter()
+            .filter(|album| album.last_played.is_some() || album.pinned)
+            .map(|album| AlbumUsage {
+                album_id: album.id,
+                last_played: album.last_played,
+                pinned: album.pinned,
+            })
+            .collect()
+    }
+
+    pub fn set_played(&mut self, id: u32) {
         if let Some(album) = self.albums.iter_mut().find(|album| album.id == id) {
-            album.download_status = DownloadStatus::DownloadFailed;
-            Some(album)
-        } else {
-            None
+            album.last_played = Some(Utc::now());
+        }
+    }
+
+    pub fn toggle_pin_selected(&mut self) {
+        if let Some(album) = self
+            .album_state
+            .selected()
+            .and_then(|index| self.albums.get_mut(index))
+        {
+            album.pinned = !album.pinned;
+        }
+    }
+
+    // Deletes the least recently played albums until the library fits in cache_size.
+    // Pinned albums, albums that are downloading and albums that are in_use are left alone.
+    // Returns the titles of the evicted albums.
+    pub fn evict(
+        &mut self,
+        cache_size: u64,
+        in_use: impl Fn(&Album) -> bool,
+    ) -> Result<Vec<String>> {
+        // Local music folders don't count, they're not in the library and can't be evicted
+        let library_size = self
+            .albums
+            .iter()
+            .filter(|album| album.downloadable)
+            .map(Album::size_on_disk)
+            .sum();
+        let candidates = self
+            .albums
+            .iter()
+            .filter(|album| {
+                !album.pinned
+                    && album.downloadable
+                    && album.download_status() != DownloadStatus::Downloading
+                    && !in_use(album)
+            })
+            .map(|album| Candidate {
+                album_id: album.id,
+                size: album.size_on_disk(),
+                last_played: album.last_played,
+            })
+            .filter(|candidate| candidate.size > 0)
+            .collect();
+
+        let mut evicted = Vec::new();
+        for id in pick_evictions(candidates, library_size, cache_size) {
+            if let Some(album) = self.albums.iter_mut().find(|album| album.id == id) {
+                album.delete_files(&self.library_root)?;
+                evicted.push(album.title.clone());
+            }
+        }
+        Ok(evicted)
+    }
+
+    // Checks every track that's on disk (see Manifest::verify), and marks the damaged ones
+    // Corrupt so they get downloaded again
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        // Music that can't be downloaded isn't ours to keep checksums for, and couldn't be
+        // replaced anyway
+        for album in self.albums.iter_mut().filter(|album| album.downloadable) {
+            let Some(directory) = album.directory().filter(|dir| dir.exists()) else {
+                continue;
+            };
+
+            let mut manifest = Manifest::load(&directory)?;
+            for track in &mut album.tracks {
+                if track.status != TrackStatus::Complete {
+                    continue;
+                }
+
+                report.checked += 1;
+                if let Err(damage) = manifest.verify(&track.file_path)? {
+                    track.status = TrackStatus::Corrupt;
+                    report.damaged.push(DamagedTrack {
+                        album: album.title.clone(),
+                        track: track.title.clone(),
+                        file_path: track.file_path.clone(),
+                        damage,
+                    });
+                }
+            }
+            manifest.save()?;
         }
+        Ok(report)
+    }
+
+    // Deletes the files of the tracks that failed verification
+    pub fn remove_damaged(&mut self) -> Result<()> {
+        for track in self
+            .albums
+            .iter_mut()
+            .flat_map(|album| album.tracks.iter_mut())
+        {
+            if track.status == TrackStatus::Corrupt {
+                discard_damaged_file(&track.file_path)?;
+                track.status = TrackStatus::Missing;
+            }
+        }
+        Ok(())
+    }
+
+    // Deletes a downloaded album's files (see Album::delete_files). Music that can't be downloaded
+    // again is never deleted, and albums being downloaded have to be cancelled first.
+    pub fn delete_album(&mut self, id: u32) -> Result<()> {
+        let album = self
+            .albums
+            .iter_mut()
+            .find(|album| album.id == id)
+            .ok_or_else(|| anyhow!("There's no album {id}"))?;
+        if !album.downloadable {
+            return Err(anyhow!(
+                "{} isn't downloaded from anywhere, so it's left alone",
+                album.title
+            ));
+        }
+        if album.download_status() == DownloadStatus::Downloading {
+            return Err(anyhow!(
+                "{} is downloading, cancel it first ('x')",
+                album.title
+            ));
+        }
+        album.delete_files(&self.library_root)
+    }
+
+    pub fn downloading(&self) -> impl Iterator<Item = DownloadProgress> {
+        self.albums
+            .iter()
+            .filter(|album| album.download_status() == DownloadStatus::Downloading)
+            .map(Album::download_progress)
     }
 }
 
@@ -69,13 +572,27 @@ impl Widget for &mut Collection {
             .albums
             .iter()
             .map(|album| {
-                let icon = match album.download_status {
-                    DownloadStatus::NotDownloaded => '💾',
-                    DownloadStatus::Downloading => '⏳',
-                    DownloadStatus::Downloaded => '✅',
-                    DownloadStatus::DownloadFailed => '🚨',
+                let icon = match album.download_status() {
+                    DownloadStatus::NotDownloaded => "💾".to_owned(),
+                    DownloadStatus::Downloading => {
+                        format!("⏳ {}", album.download_progress().gauge(10))
+                    }
+                    DownloadStatus::Downloaded => "✅".to_owned(),
+                    DownloadStatus::PartiallyDownloaded => "◐".to_owned(),
+                    DownloadStatus::DownloadFailed => "🚨".to_owned(),
                 };
-                format!("{} {icon}", album.title.clone())
+                let pin = match album.pinned {
+                    true => " 📌",
+                    false => "",
+                };
+                let icon = match album.downloadable {
+                    true => icon,
+                    false => format!("{icon} 📁"),
+                };
+                match album.extras.len() {
+                    0 => format!("{}{pin} {icon}", album.title.clone()),
+                    extras => format!("{}{pin} {icon} 📎{extras}", album.title.clone()),
+                }
             })
             .collect::<Vec<String>>();
 
@@ -93,79 +610,257 @@ pub struct Album {
     pub title: String,
     pub tracks: Vec<Track>,
     pub band_name: String,
-    pub download_status: DownloadStatus,
+    pub redownload_url: Option<String>,
+    // Booklets, artwork, videos, ... that only come with the full album package
+    pub extras: Vec<PathBuf>,
+    pub last_played: Option<DateTime<Utc>>,
+    pub pinned: bool,
+    pub tags: AlbumTags,
+    // Whether the source has downloads for the tracks. Albums without (e.g. from local music
+    // folders) are never downloaded, evicted or deleted.
+    pub downloadable: bool,
 }
 
 impl Album {
-    fn download(&mut self, download_manager: &DownloadManager) -> bool {
-        match self.download_status {
-            DownloadStatus::Downloading => false,
-            DownloadStatus::Downloaded => true,
-            DownloadStatus::NotDownloaded | DownloadStatus::DownloadFailed => {
-                self.download_status = DownloadStatus::Downloading;
+    pub fn directory(&self) -> Option<PathBuf> {
+        self.tracks
+            .first()
+            .and_then(|track| track.file_path.parent())
+            .map(PathBuf::from)
+    }
 
-                let tracks = self
-                    .tracks
-                    .iter()
-                    .map(|track| (track.download_url.clone(), track.file_path.clone()))
-                    .collect();
+    // The album is as far along as its least finished track
+    pub fn download_status(&self) -> DownloadStatus {
+        let statuses = || self.tracks.iter().map(|track| &track.status);
+
+        if statuses().any(|s| matches!(s, TrackStatus::Queued | TrackStatus::Downloading { .. })) {
+            DownloadStatus::Downloading
+        } else if statuses().all(|s| *s == TrackStatus::Complete) {
+            DownloadStatus::Downloaded
+        } else if statuses().any(|s| matches!(s, TrackStatus::Failed(_))) {
+            DownloadStatus::DownloadFailed
+        } else if statuses().any(|s| *s == TrackStatus::Complete) {
+            DownloadStatus::PartiallyDownloaded
+        } else {
+            DownloadStatus::NotDownloaded
+        }
+    }
+
+    // Bytes taken up by the tracks and extras that are on disk
+    pub fn size_on_disk(&self) -> u64 {
+        self.files_on_disk()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
 
-                download_manager.download(self.id, tracks);
+    // The tracks and extras that are there
+    pub fn files_on_disk(&self) -> impl Iterator<Item = &PathBuf> {
+        self.tracks
+            .iter()
+            .map(|track| &track.file_path)
+            .chain(self.extras.iter())
+            .filter(|path| path.exists())
+    }
+
+    // Removes the album's tracks and extras from disk, along with any directories that are left empty.
+    // The album can be downloaded again afterwards.
+    pub fn delete_files(&mut self, library_root: &Path) -> Result<()> {
+        for track in &mut self.tracks {
+            for path in [track.file_path.clone(), part_path(&track.file_path)] {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            track.status = TrackStatus::Missing;
+        }
+        for extra in self.extras.drain(..) {
+            if extra.exists() {
+                std::fs::remove_file(extra)?;
+            }
+        }
 
+        // The directory can have other albums' tracks in it, so only this album's checksums go
+        if let Some(directory) = self.directory().filter(|dir| dir.exists()) {
+            let mut manifest = Manifest::load(&directory)?;
+            self.tracks
+                .iter()
+                .for_each(|track| manifest.remove(&track.file_path));
+            manifest.save()?;
+            remove_empty_dirs(&directory, library_root);
+        }
+        Ok(())
+    }
+
+    fn track_tags(&self, index: usize) -> TrackTags {
+        TrackTags {
+            album: self.tags.clone(),
+            title: self.tracks[index].title.clone(),
+            number: self.tracks[index].number,
+            total: self.tracks.len().try_into().unwrap_or(u8::MAX),
+        }
+    }
+
+    pub fn download_progress(&self) -> DownloadProgress {
+        DownloadProgress::of(self.tracks.iter().map(|track| &track.status))
+    }
+
+    fn track_indexes(&self, filter: impl Fn(&TrackStatus) -> bool) -> Vec<usize> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, track)| filter(&track.status))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Tracks that were queued or downloading are whatever is on disk now. Packages can come in
+    // another format than the tracks, see in_any_audio_format.
+    fn refresh_in_progress_tracks(&mut self) {
+        for track in self.tracks.iter_mut().filter(|track| {
+            matches!(
+                track.status,
+                TrackStatus::Queued | TrackStatus::Downloading { .. }
+            )
+        }) {
+            if let Some(on_disk) = in_any_audio_format(&track.file_path) {
+                track.file_path = on_disk;
+            }
+            track.status = TrackStatus::on_disk(&track.file_path);
+        }
+    }
+
+    fn download(&mut self, download_manager: &DownloadManager, priority: bool) -> bool {
+        match self.download_status() {
+            DownloadStatus::Downloading => {
+                if priority {
+                    download_manager.prioritise(self.id);
+                }
+                false
+            }
+            DownloadStatus::Downloaded => true,
+            // Enter plays the tracks that are there, download all fills in the rest
+            DownloadStatus::PartiallyDownloaded if priority => true,
+            DownloadStatus::PartiallyDownloaded
+            | DownloadStatus::NotDownloaded
+            | DownloadStatus::DownloadFailed => {
+                let missing = self.track_indexes(|status| *status != TrackStatus::Complete);
+                self.download_tracks(missing, download_manager, priority);
                 false
             }
         }
     }
+
+    // Queues the tracks at the given indexes
+    fn download_tracks(
+        &mut self,
+        mut indexes: Vec<usize>,
+        download_manager: &DownloadManager,
+        priority: bool,
+    ) {
+        indexes.retain(|index| {
+            self.tracks
+                .get(*index)
+                .is_some_and(|track| track.download_url.is_some())
+        });
+        let tracks = indexes
+            .into_iter()
+            .map(|index| {
+                let tags = self.track_tags(index);
+                let track = &mut self.tracks[index];
+                // The download would take a damaged file for the finished track otherwise.
+                // If it can't be removed, the next verification flags it again.
+                if track.status == TrackStatus::Corrupt {
+                    let _ = discard_damaged_file(&track.file_path);
+                }
+                track.status = TrackStatus::Queued;
+                TrackDownload {
+                    index,
+                    download_url: track.download_url.clone().unwrap_or_default(),
+                    file_path: track.file_path.clone(),
+                    tags,
+                }
+            })
+            .collect();
+
+        download_manager.download(self.id, tracks, priority);
+    }
 }
 
-impl From<bandcamp::Item> for Album {
-    fn from(value: bandcamp::Item) -> Self {
-        let tracks = value
+impl Album {
+    // file_paths and download_urls go with the album's tracks, see MusicSource
+    pub fn new(
+        album: SourceAlbum,
+        file_paths: Vec<PathBuf>,
+        download_urls: Vec<Option<String>>,
+    ) -> Self {
+        let tracks = album
             .tracks
             .iter()
-            .map(|track| Track {
-                number: track.track_number,
+            .zip(file_paths)
+            .zip(download_urls)
+            .map(|((track, file_path), download_url)| Track {
+                number: track.number,
                 title: track.title.clone(),
-                download_url: track.hq_audio_url.clone(),
-                file_path: to_file_path(&value, &track),
+                duration: track.duration,
+                download_url,
+                status: TrackStatus::on_disk(&file_path),
+                file_path,
             })
             .collect::<Vec<Track>>();
+        let downloadable = tracks.iter().any(|track| track.download_url.is_some());
 
-        // figure out if everything is downloaded
-        let download_status = if tracks.iter().all(|t| t.file_path.exists()) {
-            DownloadStatus::Downloaded
-        } else {
-            DownloadStatus::NotDownloaded
+        let tags = AlbumTags {
+            artist: album.artist.clone(),
+            album: album.title.clone(),
+            year: album.year,
+            label: album.label,
+            artwork_url: album.artwork_url,
         };
-
+        // Extras are found by Collection, which knows whether the directory is the album's own
         Album {
-            id: value.tralbum_id,
-            title: format!("{} by {}", value.title, value.band_info.name),
+            id: album.id,
+            title: format!("{} by {}", album.title, album.artist),
             tracks,
-            band_name: value.band_info.name,
-            download_status,
+            band_name: album.artist,
+            redownload_url: album.package_url,
+            extras: Vec::new(),
+            last_played: None,
+            pinned: false,
+            tags,
+            downloadable,
         }
     }
 }
 
-fn to_file_path(album: &bandcamp::Item, track: &bandcamp::Track) -> PathBuf {
-    // This is incomplete, but works for now as it address the one album in my library with a problem:
-    // The album "tempor / jester" by A Unicorn Masquerade resulted in a jester subdirectory
-    fn filename_safe_char(char: char) -> bool {
-        char != '/'
+// Removes the file and its checksum
+fn discard_damaged_file(file_path: &Path) -> Result<()> {
+    if file_path.exists() {
+        std::fs::remove_file(file_path)?;
     }
+    if let Some(directory) = file_path.parent() {
+        let mut manifest = Manifest::load(directory)?;
+        manifest.remove(file_path);
+        manifest.save()?;
+    }
+    Ok(())
+}
 
-    let mut band_name = album.band_info.name.clone();
-    band_name.retain(filename_safe_char);
-    let mut album_title = album.title.clone();
-    album_title.retain(filename_safe_char);
-    // TODO: download path should definitely be partially built by some safe absolute path, (and hopefully) configurable
-    let mut path = PathBuf::from(format!("./bandcamp/{band_name}/{album_title}"));
+fn find_extras(album_dir: PathBuf) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(album_dir) else {
+        return Vec::new();
+    };
 
-    let mut track_title = track.title.clone();
-    track_title.retain(filename_safe_char);
-    path.push(format!("{:02} - {track_title}.mp3", track.track_number));
-    path
+    let mut extras = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| !path.ends_with(MANIFEST_FILE_NAME))
+        // Leftovers of downloads and unpacking
+        .filter(|path| !path.to_string_lossy().ends_with(PART_EXTENSION))
+        .filter(|path| !AUDIO_EXTENSIONS.contains(&lowercase_extension(path).as_str()))
+        .collect::<Vec<PathBuf>>();
+    extras.sort();
+    extras
 }
 
 #[derive(PartialEq, Clone)]
@@ -173,6 +868,8 @@ pub enum DownloadStatus {
     NotDownloaded,
     Downloading,
     Downloaded,
+    // Some tracks are on disk, the others were never asked for
+    PartiallyDownloaded,
     DownloadFailed,
 }
 
@@ -180,6 +877,62 @@ pub enum DownloadStatus {
 pub struct Track {
     pub number: u8,
     pub title: String,
-    pub download_url: String,
+    // None when the source doesn't know
+    pub duration: Option<Duration>,
+    // None when the track can't be downloaded
+    pub download_url: Option<String>,
     pub file_path: PathBuf,
+    pub status: TrackStatus,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackStatus {
+    Missing,
+    Queued,
+    Downloading { received: u64, total: Option<u64> },
+    Complete,
+    Failed(TrackFailure),
+    // On disk, but not playable
+    Corrupt,
+}
+
+impl TrackStatus {
+    pub fn on_disk(file_path: &Path) -> Self {
+        match file_path.exists() {
+            true => TrackStatus::Complete,
+            false => TrackStatus::Missing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::TestSource;
+
+    #[test]
+    fn tracks_put_in_the_library_are_found_in_any_format() {
+        let library_root = TestSource::library_root("refresh");
+        let source = TestSource {
+            library_root: library_root.clone(),
+            albums: vec![(1, "Album")],
+            track_path: |album, track| format!("{}/{}.mp3", album.title, track.number),
+        };
+        let mut collection = Collection::from_sources(&[&source], library_root.clone());
+        let flac = library_root.join("Album/1.flac");
+        std::fs::create_dir_all(flac.parent().unwrap()).unwrap();
+        std::fs::write(&flac, b"flac").unwrap();
+
+        let (appeared, gone) = collection.refresh_paths(std::slice::from_ref(&flac));
+        assert_eq!((appeared, gone), (vec![flac.clone()], vec![]));
+        let track = &collection.album(1).unwrap().tracks[0];
+        assert_eq!(track.status, TrackStatus::Complete);
+        assert_eq!(track.file_path, flac);
+
+        std::fs::remove_file(&flac).unwrap();
+        let (appeared, gone) = collection.refresh_paths(std::slice::from_ref(&flac));
+        assert_eq!((appeared, gone), (vec![], vec![flac]));
+
+        std::fs::remove_dir_all(&library_root).unwrap();
+    }
 }
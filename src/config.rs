@@ -0,0 +1,71 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::library::DEFAULT_TEMPLATE;
+use crate::tags::TagMode;
+use crate::throttle::DownloadWindow;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // One of the formats offered on Bandcamp's download page, e.g. "mp3-320", "flac", "alac"
+    pub package_format: String,
+    pub max_concurrent_downloads: usize,
+    pub max_downloads_per_host: usize,
+    // How many times a track download is tried before giving up on it
+    pub download_attempts: u32,
+    // Limit on the combined download speed in bytes per second, no limit if left out
+    pub max_download_rate: Option<u64>,
+    // When downloads the user didn't ask for right now (download all, resumed queue) may run,
+    // e.g. [{ "start": "01:00", "end": "07:00" }]. Any time if left empty.
+    pub bulk_download_windows: Vec<DownloadWindow>,
+    // Downloads that would grow the library past this many bytes are turned down, no limit if left out
+    pub max_library_size: Option<u64>,
+    // Cache mode: when the library grows past this many bytes, the albums that haven't been
+    // played for longest are deleted (unless they're pinned). Off if left out.
+    pub cache_size: Option<u64>,
+    // Where downloads go. Relative paths are relative to the working directory.
+    pub library_root: PathBuf,
+    // Where in the library each track goes, see library::PathTemplate
+    pub path_template: String,
+    // What downloaded tracks get tagged with: "overwrite", "fill-missing" or "skip".
+    // Only MP3 and FLAC files are tagged.
+    pub tag_mode: TagMode,
+    // Folders of music from elsewhere (CD rips, other stores, ...) to show alongside the collection
+    pub local_music_dirs: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            package_format: "mp3-320".to_owned(),
+            max_concurrent_downloads: 6,
+            max_downloads_per_host: 4,
+            download_attempts: 3,
+            max_download_rate: None,
+            bulk_download_windows: Vec::new(),
+            max_library_size: None,
+            cache_size: None,
+            library_root: PathBuf::from("bandcamp"),
+            path_template: DEFAULT_TEMPLATE.to_owned(),
+            tag_mode: TagMode::FillMissing,
+            local_music_dirs: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // A missing config file isn't an error, it just means everything is defaulted
+    pub fn load(path: &Path) -> Result<Self> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
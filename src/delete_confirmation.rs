@@ -0,0 +1,46 @@
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Paragraph, Widget, Wrap};
+
+use crate::collection::Album;
+use crate::progress::format_bytes;
+
+// Asks before a downloaded album is deleted, and says what that would free up
+pub struct DeleteConfirmation {
+    pub album_id: u32,
+    title: String,
+    files: usize,
+    bytes: u64,
+}
+
+impl DeleteConfirmation {
+    pub fn new(album: &Album) -> Self {
+        Self {
+            album_id: album.id,
+            title: album.title.clone(),
+            files: album.files_on_disk().count(),
+            bytes: album.size_on_disk(),
+        }
+    }
+
+    pub fn render(&self, area: Rect, buf: &mut Buffer) {
+        let text = vec![
+            Line::from(format!("Delete {}?", self.title)),
+            Line::from(""),
+            Line::from(format!(
+                "{} files, {} freed",
+                self.files,
+                format_bytes(self.bytes as f64)
+            )),
+            Line::from("Folders left empty are removed too. The album can be downloaded again."),
+        ];
+
+        Paragraph::new(text)
+            .wrap(Wrap { trim: true })
+            .block(
+                Block::bordered()
+                    .title("Delete album")
+                    .title_bottom("'y' delete | 'n/esc' cancel"),
+            )
+            .render(area, buf);
+    }
+}
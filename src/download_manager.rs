@@ -1,79 +1,894 @@
 use anyhow::{Result, anyhow};
-use reqwest::{Client, StatusCode};
-use std::{fs::create_dir_all, path::PathBuf, sync::mpsc::Sender};
-use tokio::runtime::Runtime;
+use chrono::Local;
+use reqwest::{
+    Client, Response, StatusCode,
+    header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, create_dir_all},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc::Sender},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    io::AsyncWriteExt,
+    runtime::Runtime,
+    sync::{Notify, Semaphore},
+    task::AbortHandle,
+};
 
+use crate::bandcamp;
+use crate::collection::Collection;
+use crate::config::Config;
+use crate::download_queue::{DownloadQueue, QueuedAlbum, TrackDownload, TrackFailure, TrackJob};
 use crate::events::Event;
+use crate::json_l::{read_lines_from_file, replace_file_lines};
+use crate::storage::{
+    SpaceError, check_space, directory_size, free_space, lowercase_extension,
+    write_through_part_file,
+};
+use crate::tags::{Artwork, TagMode, TrackTags, write_tags};
+use crate::throttle::{DownloadWindow, RateLimiter, in_download_window};
+
+pub(crate) const PART_EXTENSION: &str = ".part";
+// No point in sending progress events faster than the UI draws them
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// How often the dispatcher looks at the clock when bulk downloads are limited to download windows
+const WINDOW_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// How often an album waiting for its size check looks whether the user asked for it meanwhile
+const PRIORITY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// The longest wait between attempts at downloading a track
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// Where the cover art fetched from Bandcamp is saved, for Artwork::find to pick up
+const COVER_FILE_NAME: &str = "cover.jpg";
+// What album packages are downloaded as, before they're unpacked
+const PACKAGE_FILE_NAME: &str = "package.zip";
+
+// Anything in an album package with one of these extensions is music, everything else is an extra
+pub const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "flac", "ogg", "m4a", "aac", "wav", "aiff", "alac"];
+
+// Where a track is on disk, if it's there in any format. Tracks from packages and imports keep the
+// format they came in (FLAC, ...), whatever extension their path was planned with.
+pub fn in_any_audio_format(path: &Path) -> Option<PathBuf> {
+    match path.exists() {
+        true => Some(path.to_owned()),
+        false => AUDIO_EXTENSIONS
+            .iter()
+            .map(|extension| path.with_extension(extension))
+            .find(|path| path.exists()),
+    }
+}
+
+// Whether the paths are of the same track, in whatever formats
+pub fn is_same_track(path: &Path, other: &Path) -> bool {
+    path.with_extension("") == other.with_extension("")
+}
 
 pub struct DownloadManager {
     download_runtime: Runtime,
     client: reqwest::Client,
     mpsc_tx: Sender<Event>,
+    queue: Arc<SharedQueue>,
+    // Shared by every download, so the limit is on the combined speed
+    rate_limiter: Arc<RateLimiter>,
+    // Package downloads don't go through the queue, but still need to be cancellable
+    packages: Arc<Mutex<HashMap<u32, AbortHandle>>>,
+    // Albums only join the queue once they've been checked to fit on disk
+    preflights: Arc<Mutex<HashMap<u32, AbortHandle>>>,
+    // Albums asked for (see prioritise) while they were still being checked
+    prioritised: Arc<Mutex<HashSet<u32>>>,
+    // Keeps a download all from firing off a size request for every track at once
+    preflight_permits: Arc<Semaphore>,
+    library_root: PathBuf,
+    max_library_size: Option<u64>,
+    tag_mode: TagMode,
+}
+
+// The queue is shared by the UI thread (adding, cancelling, ...) and the download tasks (finishing)
+struct SharedQueue {
+    queue: Mutex<DownloadQueue>,
+    changed: Notify,
+    path: PathBuf,
+    mpsc_tx: Sender<Event>,
+}
+
+impl SharedQueue {
+    // Every change gets saved to disk and wakes up the dispatcher
+    fn update<T>(&self, change: impl FnOnce(&mut DownloadQueue) -> T) -> T {
+        let mut queue = self.queue.lock().unwrap();
+        let result = change(&mut queue);
+
+        if let Err(err) = save_queue(&queue, &self.path) {
+            let _ = self.mpsc_tx.send(Event::Error(err));
+        }
+        self.changed.notify_one();
+
+        result
+    }
 }
 
 impl DownloadManager {
-    pub fn new(mpsc_tx: Sender<Event>, download_runtime: Runtime) -> Self {
+    // queue_path is where the queue is saved, so downloads can carry on after a restart.
+    // library_root is where the downloads end up, for the disk space checks.
+    pub fn new(
+        mpsc_tx: Sender<Event>,
+        download_runtime: Runtime,
+        config: &Config,
+        queue_path: PathBuf,
+        library_root: PathBuf,
+    ) -> Self {
+        let client = reqwest::Client::default();
+        let rate_limiter = Arc::new(RateLimiter::new(config.max_download_rate));
+        let queue = Arc::new(SharedQueue {
+            queue: Mutex::new(DownloadQueue::new(
+                config.max_concurrent_downloads,
+                config.max_downloads_per_host,
+            )),
+            changed: Notify::new(),
+            path: queue_path,
+            mpsc_tx: mpsc_tx.clone(),
+        });
+
+        download_runtime.spawn(dispatch(
+            client.clone(),
+            mpsc_tx.clone(),
+            queue.clone(),
+            rate_limiter.clone(),
+            config.download_attempts.max(1),
+            config.bulk_download_windows.clone(),
+            config.tag_mode,
+        ));
+
         Self {
             download_runtime,
-            client: reqwest::Client::default(),
+            client,
             mpsc_tx,
+            queue,
+            rate_limiter,
+            packages: Arc::new(Mutex::new(HashMap::new())),
+            preflights: Arc::new(Mutex::new(HashMap::new())),
+            prioritised: Arc::new(Mutex::new(HashSet::new())),
+            preflight_permits: Arc::new(Semaphore::new(config.max_concurrent_downloads.max(1))),
+            library_root,
+            max_library_size: config.max_library_size,
+            tag_mode: config.tag_mode,
         }
     }
 
-    pub fn download(&self, album_id: u32, tracks: Vec<(String, PathBuf)>) {
+    // Priority downloads (the album the user wants to hear now) skip ahead of everything else,
+    // and don't have to wait for a download window.
+    // Before it's queued, the album's size is checked against the free disk space and the
+    // library size limit. Albums that don't fit fail straight away.
+    pub fn download(&self, album_id: u32, tracks: Vec<TrackDownload>, priority: bool) {
+        if tracks.is_empty() {
+            self.mpsc_tx.send(Event::AlbumDownloaded(album_id)).unwrap();
+            return;
+        }
+
         let client = self.client.clone();
         let notify = self.mpsc_tx.clone();
+        let queue = self.queue.clone();
+        let library_root = self.library_root.clone();
+        let max_library_size = self.max_library_size;
+        let permits = self.preflight_permits.clone();
+        let prioritised = self.prioritised.clone();
+        let is_prioritised = {
+            let prioritised = prioritised.clone();
+            move || priority || prioritised.lock().unwrap().contains(&album_id)
+        };
+        let preflights = self.preflights.clone();
+
+        // Held until the task is in preflights, so the task can't remove itself before that
+        let mut preflights_lock = self.preflights.lock().unwrap();
+        let preflight = self.download_runtime.spawn(async move {
+            let needed = {
+                // The album the user is waiting on doesn't wait behind the checks of a download all,
+                // even when it's asked for after the checks started
+                let _permit = loop {
+                    if is_prioritised() {
+                        break None;
+                    }
+                    if let Ok(permit) =
+                        tokio::time::timeout(PRIORITY_CHECK_INTERVAL, permits.acquire()).await
+                    {
+                        break permit.ok();
+                    }
+                };
+                estimate_download_size(&client, &tracks).await
+            };
+
+            let used = match max_library_size {
+                Some(_) => {
+                    let library_root = library_root.clone();
+                    tokio::task::spawn_blocking(move || directory_size(&library_root))
+                        .await
+                        .unwrap_or(0)
+                }
+                None => 0,
+            };
 
-        self.download_runtime.spawn(async move {
-            let mut join_set = tokio::task::JoinSet::new();
+            let indexes = tracks.iter().map(|track| track.index).collect::<Vec<_>>();
+            let queued = queue.update(|queue| {
+                let reserved = queue.reserved_bytes();
+                let available = free_space(&library_root)?.saturating_sub(reserved);
+                check_space(needed, available, used + reserved, max_library_size)?;
 
-            tracks.into_iter().for_each(|(download_url, file_path)| {
-                join_set.spawn(download_track_async(
-                    client.clone(),
-                    file_path,
-                    download_url,
-                ));
+                queue.push_album(album_id, tracks, is_prioritised());
+                queue.reserve(album_id, needed);
+                anyhow::Ok(())
             });
+            preflights.lock().unwrap().remove(&album_id);
+            prioritised.lock().unwrap().remove(&album_id);
 
-            if let Some(first_failure) = join_set
-                .join_all()
-                .await
-                .into_iter()
-                .find(|result| result.is_err())
-            {
+            if let Err(err) = queued {
+                let failures = indexes
+                    .into_iter()
+                    .map(|index| TrackFailure {
+                        index,
+                        error: format!("{err:#}"),
+                        attempts: 0,
+                    })
+                    .collect();
                 notify
-                    .send(Event::AlbumDownLoadFailed(
-                        album_id,
-                        first_failure.err().unwrap(),
-                    ))
+                    .send(Event::AlbumDownLoadFailed(album_id, failures))
                     .unwrap();
+            }
+        });
+
+        preflights_lock.insert(album_id, preflight.abort_handle());
+    }
+
+    pub fn prioritise(&self, album_id: u32) {
+        // Albums still being checked are picked up by the check (see download). It joins the
+        // queue under the same lock, so the album is either in the queue or still being checked.
+        self.queue.update(|queue| {
+            if !queue.prioritise(album_id)
+                && self.preflights.lock().unwrap().contains_key(&album_id)
+            {
+                self.prioritised.lock().unwrap().insert(album_id);
+            }
+        });
+    }
+
+    pub fn cancel(&self, album_id: u32) {
+        let abort_handles = self.queue.update(|queue| queue.cancel_album(album_id));
+        abort_handles.iter().for_each(AbortHandle::abort);
+
+        if let Some(package) = self.packages.lock().unwrap().remove(&album_id) {
+            package.abort();
+        }
+        if let Some(preflight) = self.preflights.lock().unwrap().remove(&album_id) {
+            preflight.abort();
+        }
+        self.prioritised.lock().unwrap().remove(&album_id);
+    }
+
+    pub fn cancel_all(&self) {
+        let abort_handles = self.queue.update(DownloadQueue::cancel_all);
+        abort_handles.iter().for_each(AbortHandle::abort);
+
+        self.packages
+            .lock()
+            .unwrap()
+            .drain()
+            .chain(self.preflights.lock().unwrap().drain())
+            .for_each(|(_, task)| task.abort());
+        self.prioritised.lock().unwrap().clear();
+    }
+
+    // Returns whether the queue is paused now
+    pub fn toggle_pause(&self) -> bool {
+        self.queue.update(|queue| {
+            if queue.is_paused() {
+                queue.resume();
+                false
             } else {
-                notify.send(Event::AlbumDownloaded(album_id)).unwrap();
+                queue.pause().iter().for_each(AbortHandle::abort);
+                true
+            }
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.queue.queue.lock().unwrap().is_paused()
+    }
+
+    // Whether bulk downloads are waiting for a download window to open
+    pub fn is_waiting_for_window(&self) -> bool {
+        self.queue.queue.lock().unwrap().is_bulk_on_hold()
+    }
+
+    // Downloads the album package (the zip you get from the Bandcamp website) in the given format
+    // and unpacks it into album_dir. Tracks are matched by number to the (track_number, file_path)
+    // pairs so the player finds them where it expects them, and tagged with the tags for their number.
+    // Packages that don't fit on disk (or in the library size limit) fail before they're written.
+    pub fn download_package(
+        &self,
+        album_id: u32,
+        redownload_url: String,
+        format: String,
+        album_dir: PathBuf,
+        tracks: Vec<(u8, PathBuf)>,
+        tags: Vec<TrackTags>,
+    ) {
+        let client = self.client.clone();
+        let notify = self.mpsc_tx.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let tag_mode = self.tag_mode;
+        let packages = self.packages.clone();
+        let queue = self.queue.clone();
+        let library_root = self.library_root.clone();
+        let max_library_size = self.max_library_size;
+        // Same as the check of other downloads, with what the queued ones have reserved
+        let check_fits = move |needed: u64| {
+            let used = match max_library_size {
+                Some(_) => directory_size(&library_root),
+                None => 0,
+            };
+            let reserved = queue.queue.lock().unwrap().reserved_bytes();
+            let available = free_space(&library_root)?.saturating_sub(reserved);
+            check_space(needed, available, used + reserved, max_library_size)?;
+            anyhow::Ok(())
+        };
+
+        // Held until the task is in packages, so the task can't remove itself before that
+        let mut packages_lock = self.packages.lock().unwrap();
+        let package = self.download_runtime.spawn(async move {
+            let result = download_package_async(
+                client,
+                redownload_url,
+                format,
+                album_dir.clone(),
+                tracks,
+                &rate_limiter,
+                check_fits,
+            )
+            .await;
+
+            // The tracks are there either way, so tagging failures don't fail the download
+            if let Ok(unpacked) = &result
+                && tag_mode != TagMode::Skip
+            {
+                let unpacked = unpacked.tracks.clone();
+                let tagged = tokio::task::spawn_blocking(move || {
+                    let artwork = Artwork::find(&album_dir)?;
+                    for (number, file_path) in &unpacked {
+                        if let Some(tags) = tags.iter().find(|tags| tags.number == *number) {
+                            write_tags(file_path, tags, artwork.as_ref(), tag_mode)?;
+                        }
+                    }
+                    anyhow::Ok(())
+                })
+                .await;
+                if let Err(err) = tagged.map_err(anyhow::Error::from).and_then(|r| r) {
+                    let _ = notify.send(Event::Error(err.context("Tagging failed")));
+                }
+            }
+
+            packages.lock().unwrap().remove(&album_id);
+            match result {
+                Ok(unpacked) => notify
+                    .send(Event::AlbumPackageDownloaded(album_id, unpacked.extras))
+                    .unwrap(),
+                Err(err) => notify
+                    .send(Event::PackageDownloadFailed(album_id, err))
+                    .unwrap(),
+            }
+        });
+
+        packages_lock.insert(album_id, package.abort_handle());
+    }
+}
+
+// Starts as many queued tracks as the queue allows, then waits for the queue to change
+// (or for the clock to move on, when there are download windows)
+async fn dispatch(
+    client: Client,
+    notify: Sender<Event>,
+    queue: Arc<SharedQueue>,
+    rate_limiter: Arc<RateLimiter>,
+    max_attempts: u32,
+    windows: Vec<DownloadWindow>,
+    tag_mode: TagMode,
+) {
+    loop {
+        let tracks = {
+            let mut queue = queue.queue.lock().unwrap();
+            queue.hold_bulk(!in_download_window(&windows, Local::now().time()));
+            std::iter::from_fn(|| queue.next_track()).collect::<Vec<TrackJob>>()
+        };
+
+        for track in tracks {
+            let client = client.clone();
+            let notify = notify.clone();
+            let task_queue = queue.clone();
+            let rate_limiter = rate_limiter.clone();
+
+            let task_track = track.clone();
+            let task = tokio::spawn(async move {
+                let track = task_track;
+                let (album_id, index) = (track.album_id, track.index);
+                let progress_notify = notify.clone();
+                let mut on_progress = |received, total| {
+                    let _ = progress_notify.send(Event::TrackDownloadProgress(
+                        album_id, index, received, total,
+                    ));
+                };
+
+                let mut attempts = 0;
+                let result = loop {
+                    attempts += 1;
+                    let result = download_track_async(
+                        client.clone(),
+                        track.file_path.clone(),
+                        track.download_url.clone(),
+                        &rate_limiter,
+                        &mut on_progress,
+                    )
+                    .await;
+
+                    match result {
+                        Err(err) if attempts < max_attempts && is_worth_retrying(&err) => {
+                            // Back off a little more each time: 1s, 2s, 4s, ... up to a minute
+                            let backoff = Duration::from_secs(1 << (attempts - 1).min(6));
+                            tokio::time::sleep(backoff.min(MAX_RETRY_DELAY)).await;
+                        }
+                        result => {
+                            break result.map_err(|err| TrackFailure {
+                                index,
+                                error: format!("{err:#}"),
+                                attempts,
+                            });
+                        }
+                    }
+                };
+
+                if result.is_ok() {
+                    // The track is there either way, so a tagging failure doesn't fail the download
+                    if tag_mode != TagMode::Skip
+                        && let Err(err) = tag_track(&client, &track, tag_mode, &rate_limiter).await
+                    {
+                        let _ = notify.send(Event::Error(err.context("Tagging failed")));
+                    }
+                    notify
+                        .send(Event::TrackDownloaded(album_id, index))
+                        .unwrap();
+                }
+
+                let outcome = task_queue.update(|queue| queue.finish_track(track, result));
+                match outcome {
+                    Some((album_id, Ok(()))) => {
+                        notify.send(Event::AlbumDownloaded(album_id)).unwrap()
+                    }
+                    Some((album_id, Err(failures))) => notify
+                        .send(Event::AlbumDownLoadFailed(album_id, failures))
+                        .unwrap(),
+                    None => (),
+                }
+            });
+
+            queue
+                .queue
+                .lock()
+                .unwrap()
+                .set_abort_handle(&track, task.abort_handle());
+        }
+
+        match windows.is_empty() {
+            true => queue.changed.notified().await,
+            false => {
+                let _ = tokio::time::timeout(WINDOW_CHECK_INTERVAL, queue.changed.notified()).await;
             }
+        }
+    }
+}
+
+// Adds up the sizes the server gives for the tracks, less whatever is already in .part files.
+// Tracks it won't give a size for count as nothing, and get checked when they download instead.
+async fn estimate_download_size(client: &Client, tracks: &[TrackDownload]) -> u64 {
+    let mut total = 0;
+    for track in tracks {
+        let Ok(response) = client.head(&track.download_url).send().await else {
+            continue;
+        };
+        // content_length() is about the (empty) body of a HEAD response, so read the header itself
+        let size = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+            .filter(|_| response.status().is_success())
+            .unwrap_or(0);
+        let partial = tokio::fs::metadata(part_path(&track.file_path))
+            .await
+            .map_or(0, |metadata| metadata.len());
+
+        total += size.saturating_sub(partial);
+    }
+    total
+}
+
+// Tags the downloaded track. The cover art comes from the album directory if there's a cover
+// file there, Bandcamp otherwise. What Bandcamp sends is saved as the album's cover file, so it's
+// only fetched once per album.
+async fn tag_track(
+    client: &Client,
+    track: &TrackJob,
+    tag_mode: TagMode,
+    rate_limiter: &RateLimiter,
+) -> Result<()> {
+    let album_dir = track.file_path.parent().map(PathBuf::from);
+    let mut artwork = match album_dir.clone() {
+        Some(album_dir) => tokio::task::spawn_blocking(move || Artwork::find(&album_dir)).await??,
+        None => None,
+    };
+    if artwork.is_none()
+        && let Some(url) = &track.tags.album.artwork_url
+    {
+        let data = ok_or_status_error(client.get(url).send().await?)?
+            .bytes()
+            .await?;
+        rate_limiter.take(data.len()).await;
+        if let Some(album_dir) = &album_dir {
+            // Another track of the album can be saving it at the same time, the cover is there either way
+            let (cover_path, data) = (album_dir.join(COVER_FILE_NAME), data.clone());
+            let _ = tokio::task::spawn_blocking(move || {
+                write_through_part_file(&cover_path, data.as_ref())
+            })
+            .await;
+        }
+        artwork = Some(Artwork {
+            mime_type: "image/jpeg".to_owned(),
+            data: data.to_vec(),
         });
     }
+
+    let file_path = track.file_path.clone();
+    let tags = track.tags.clone();
+    tokio::task::spawn_blocking(move || write_tags(&file_path, &tags, artwork.as_ref(), tag_mode))
+        .await?
+}
+
+fn save_queue(queue: &DownloadQueue, queue_path: &Path) -> Result<()> {
+    replace_file_lines(queue_path, queue.snapshot().iter())
+}
+
+// What was still queued when the app last exited
+pub fn load_queue(queue_path: &Path) -> Result<Vec<QueuedAlbum>> {
+    match File::open(queue_path) {
+        Ok(file) => read_lines_from_file(file),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
 }
 
-async fn download_track_async(client: Client, path: PathBuf, download_url: String) -> Result<()> {
+async fn download_track_async(
+    client: Client,
+    path: PathBuf,
+    download_url: String,
+    rate_limiter: &RateLimiter,
+    on_progress: impl FnMut(u64, Option<u64>),
+) -> Result<()> {
     if path.exists() {
         return Ok(());
     }
 
-    let download_response = client.get(download_url).send().await?;
+    create_dir_all(path.parent().unwrap())?;
 
-    match download_response.status() {
-        StatusCode::OK => {
-            create_dir_all(path.parent().unwrap())?;
+    // A .part file left by an interrupted download can be picked up where it left off
+    let resume_from = tokio::fs::metadata(part_path(&path))
+        .await
+        .map_or(0, |metadata| metadata.len());
 
-            let bytes = download_response.bytes().await?;
-            tokio::fs::write(&path, bytes).await?;
+    let mut request = client.get(&download_url);
+    if resume_from > 0 {
+        request = request.header(RANGE, format!("bytes={resume_from}-"));
+    }
+    let download_response = request.send().await?;
 
-            Ok(())
+    let (download_response, offset) = match download_response.status() {
+        // Either a fresh download, or the server ignored the range and sent everything
+        StatusCode::OK => (download_response, 0),
+        StatusCode::PARTIAL_CONTENT
+            if content_range_start(&download_response) == Some(resume_from) =>
+        {
+            (download_response, resume_from)
+        }
+        // The .part file doesn't line up with what the server has, so start from scratch
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+            tokio::fs::remove_file(part_path(&path)).await?;
+            (
+                ok_or_status_error(client.get(&download_url).send().await?)?,
+                0,
+            )
         }
         // Bandcamp URLs eventually return a 410-Gone response when the download link is no longer valid
-        _ => Err(anyhow!(
-            "Download status code: {}",
-            download_response.status()
-        )),
+        status => return Err(StatusError(status).into()),
+    };
+
+    // Other downloads may have used up the space the album was checked against
+    if let Some(len) = download_response.content_length() {
+        check_space(len, free_space(&path)?, 0, None)?;
+    }
+
+    write_response_to_file(download_response, &path, offset, rate_limiter, on_progress).await
+}
+
+#[derive(Debug, Error)]
+#[error("Download status code: {0}")]
+pub struct StatusError(pub StatusCode);
+
+// Client errors (410 Gone, 404 Not Found, ...) and a full disk won't go away by asking again
+fn is_worth_retrying(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<SpaceError>().is_none()
+        && err
+            .downcast_ref::<StatusError>()
+            .is_none_or(|StatusError(status)| !status.is_client_error())
+}
+
+fn ok_or_status_error(response: Response) -> Result<Response> {
+    match response.status() {
+        StatusCode::OK => Ok(response),
+        status => Err(StatusError(status).into()),
+    }
+}
+
+// Content-Range looks like "bytes 1000-1999/2000"
+fn content_range_start(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .parse()
+        .ok()
+}
+
+async fn download_package_async(
+    client: Client,
+    redownload_url: String,
+    format: String,
+    album_dir: PathBuf,
+    tracks: Vec<(u8, PathBuf)>,
+    rate_limiter: &RateLimiter,
+    check_fits: impl FnOnce(u64) -> Result<()> + Send + 'static,
+) -> Result<Unpacked> {
+    let download_page = client.get(redownload_url).send().await?.text().await?;
+    let package_url = bandcamp::parse_package_url(&download_page, &format)?;
+
+    let package_response = ok_or_status_error(client.get(package_url).send().await?)?;
+    // The package and what's unpacked from it (about as big, music hardly compresses) are on
+    // disk together for a while
+    if let Some(package_size) = package_response.content_length() {
+        tokio::task::spawn_blocking(move || check_fits(package_size * 2)).await??;
+    }
+
+    create_dir_all(&album_dir)?;
+    let package_path = album_dir.join(PACKAGE_FILE_NAME);
+    write_response_to_file(package_response, &package_path, 0, rate_limiter, |_, _| ()).await?;
+
+    tokio::task::spawn_blocking(move || {
+        let unpacked = unpack_package(&package_path, &album_dir, &tracks);
+        std::fs::remove_file(&package_path)?;
+        unpacked
+    })
+    .await?
+}
+
+// Streams the body into a .part file next to path, which only gets renamed to path once
+// everything the server promised has arrived and is flushed to disk. That way anything
+// sitting at path is known to be complete.
+// A non-zero offset means the response continues the existing .part file.
+// on_progress gets (bytes received, bytes expected), at most every PROGRESS_INTERVAL
+async fn write_response_to_file(
+    mut response: Response,
+    path: &Path,
+    offset: u64,
+    rate_limiter: &RateLimiter,
+    mut on_progress: impl FnMut(u64, Option<u64>),
+) -> Result<()> {
+    let part_path = part_path(path);
+    let expected_len = response.content_length().map(|len| offset + len);
+
+    let mut file = match offset {
+        0 => tokio::fs::File::create(&part_path).await?,
+        _ => {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&part_path)
+                .await?
+        }
+    };
+    let mut received = offset;
+    let mut last_progress = Instant::now();
+    on_progress(received, expected_len);
+    while let Some(chunk) = response.chunk().await? {
+        rate_limiter.take(chunk.len()).await;
+        file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            on_progress(received, expected_len);
+            last_progress = Instant::now();
+        }
+    }
+    on_progress(received, expected_len);
+    file.flush().await?;
+    file.sync_all().await?;
+
+    if let Some(expected_len) = expected_len
+        && expected_len != received
+    {
+        // Too little can be resumed later, too much means the .part file is garbage
+        if received > expected_len {
+            tokio::fs::remove_file(&part_path).await?;
+        }
+        return Err(anyhow!(
+            "Download incomplete: received {received} of {expected_len} bytes"
+        ));
+    }
+
+    tokio::fs::rename(&part_path, path).await?;
+    Ok(())
+}
+
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(PART_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+// Interrupted downloads (the app quit, the machine crashed, ...) leave .part files behind.
+// The ones of tracks that aren't downloaded yet get picked up by the next download of their
+// track, the rest are removed. Only the .part files this app makes (tracks in any format,
+// packages and covers) are looked for, the library root can be shared with other programs.
+pub fn remove_partial_downloads(collection: &Collection) -> Result<()> {
+    let resumable = collection.resumable_downloads();
+    for album in collection.albums().filter(|album| album.downloadable) {
+        let Some(album_dir) = album.directory() else {
+            continue;
+        };
+        let tracks = album.tracks.iter().flat_map(|track| {
+            AUDIO_EXTENSIONS
+                .iter()
+                .map(|extension| track.file_path.with_extension(extension))
+        });
+        let others = [PACKAGE_FILE_NAME, COVER_FILE_NAME].map(|name| album_dir.join(name));
+
+        for path in tracks.chain(others).map(|path| part_path(&path)) {
+            if path.is_file() && !resumable.contains(&path) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Unpacks the package into album_dir. Tracks are matched by number and go where the
+// (track_number, file_path) pairs say, in the package's format. Everything else lands in album_dir.
+// Files go through a .part file, so an interrupted unpack doesn't leave half a track that looks
+// complete, and tracks that are already there are left alone.
+fn unpack_package(
+    package_path: &Path,
+    album_dir: &Path,
+    tracks: &[(u8, PathBuf)],
+) -> Result<Unpacked> {
+    let mut archive = zip::ZipArchive::new(File::open(package_path)?)?;
+    let mut unpacked = Vec::new();
+    let mut extras = Vec::new();
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        // enclosed_name guards against entries like "../../.bashrc"
+        let Some(file_name) = entry
+            .enclosed_name()
+            .and_then(|name| name.file_name().map(PathBuf::from))
+        else {
+            continue;
+        };
+        if entry.is_dir() {
+            continue;
+        }
+
+        let extension = lowercase_extension(&file_name);
+        let is_audio = AUDIO_EXTENSIONS.contains(&extension.as_str());
+
+        let track = is_audio
+            .then(|| track_number_from_file_name(&file_name))
+            .flatten()
+            .and_then(|number| tracks.iter().find(|(n, _)| *n == number));
+        let target = match track {
+            Some((_, path)) if in_any_audio_format(path).is_some() => continue,
+            Some((number, path)) => {
+                let target = path.with_extension(&extension);
+                unpacked.push((*number, target.clone()));
+                target
+            }
+            None => album_dir.join(&file_name),
+        };
+
+        if !is_audio {
+            extras.push(target.clone());
+        }
+        if target.exists() {
+            continue;
+        }
+
+        write_through_part_file(&target, &mut entry)?;
+    }
+
+    Ok(Unpacked {
+        tracks: unpacked,
+        extras,
+    })
+}
+
+// What came out of a package
+struct Unpacked {
+    // (track_number, file_path) of the tracks
+    tracks: Vec<(u8, PathBuf)>,
+    // Booklets, artwork, videos, ...
+    extras: Vec<PathBuf>,
+}
+
+// Bandcamp names packaged tracks "{band} - {album} - {nn} {title}.{ext}"
+pub(crate) fn track_number_from_file_name(file_name: &Path) -> Option<u8> {
+    let stem = file_name.file_stem()?.to_str()?;
+    stem.rsplit(" - ").find_map(|part| {
+        part.split_once(' ')
+            .and_then(|(number, _)| number.parse::<u8>().ok())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_number_is_parsed_from_package_file_names() {
+        let number = |name: &str| track_number_from_file_name(Path::new(name));
+
+        assert_eq!(number("Band - Album - 07 Some Song.mp3"), Some(7));
+        assert_eq!(number("Band - Album - 12 Song - With Dash.flac"), Some(12));
+        assert_eq!(number("cover.jpg"), None);
+    }
+
+    #[test]
+    fn packaged_tracks_keep_their_format() {
+        let album_dir = std::env::temp_dir().join("rusty-piano-unpack-test");
+        let _ = std::fs::remove_dir_all(&album_dir);
+        create_dir_all(&album_dir).unwrap();
+        let package_path = album_dir.join("package.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&package_path).unwrap());
+        for name in ["Band - Album - 01 One.flac", "cover.jpg"] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut zip, name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let tracks = [(1, album_dir.join("01 One.mp3"))];
+        let unpacked = unpack_package(&package_path, &album_dir, &tracks).unwrap();
+
+        assert_eq!(unpacked.tracks, [(1, album_dir.join("01 One.flac"))]);
+        assert_eq!(unpacked.extras, [album_dir.join("cover.jpg")]);
+        assert!(album_dir.join("01 One.flac").exists());
+
+        // Tracks that are there already are left alone, in whatever format they are
+        std::fs::write(album_dir.join("01 One.flac"), b"complete").unwrap();
+        let unpacked = unpack_package(&package_path, &album_dir, &tracks).unwrap();
+        assert!(unpacked.tracks.is_empty());
+        assert_eq!(
+            std::fs::read(album_dir.join("01 One.flac")).unwrap(),
+            b"complete"
+        );
+        assert!(!part_path(&album_dir.join("cover.jpg")).exists());
+
+        std::fs::remove_dir_all(&album_dir).unwrap();
     }
 }
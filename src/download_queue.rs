@@ -0,0 +1,498 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use tokio::task::AbortHandle;
+
+use crate::tags::TrackTags;
+
+// A track to download, identified by its index in the album's track list
+pub struct TrackDownload {
+    pub index: usize,
+    pub download_url: String,
+    pub file_path: PathBuf,
+    pub tags: TrackTags,
+}
+
+#[derive(Clone)]
+pub struct TrackJob {
+    pub album_id: u32,
+    pub index: usize,
+    pub download_url: String,
+    pub file_path: PathBuf,
+    pub tags: TrackTags,
+    host: String,
+}
+
+// What gets saved to disk, so the queue survives a restart
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct QueuedAlbum {
+    pub album_id: u32,
+    pub priority: bool,
+    pub tracks: Vec<QueuedTrack>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct QueuedTrack {
+    pub index: usize,
+    pub state: TrackState,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum TrackState {
+    Queued,
+    Downloading,
+    Downloaded,
+    Failed,
+}
+
+// Why a track didn't download, after how many tries
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackFailure {
+    pub index: usize,
+    // The HTTP status or IO error
+    pub error: String,
+    pub attempts: u32,
+}
+
+struct InFlight {
+    track: TrackJob,
+    abort_handle: Option<AbortHandle>,
+}
+
+struct AlbumJob {
+    album_id: u32,
+    // Albums the user is waiting on get served before everything else
+    priority: bool,
+    pending: VecDeque<TrackJob>,
+    in_flight: Vec<InFlight>,
+    // Indexes of the tracks that are done, and whether they succeeded
+    finished: Vec<(usize, bool)>,
+    failures: Vec<TrackFailure>,
+    // What the album was expected to take up on disk when it was queued
+    reserved_bytes: u64,
+}
+
+// Hands out track downloads while keeping the number of concurrent downloads (overall and per host) bounded.
+// Albums take turns, so one huge album can't starve the rest of the queue.
+pub struct DownloadQueue {
+    albums: VecDeque<AlbumJob>,
+    max_concurrent: usize,
+    max_per_host: usize,
+    paused: bool,
+    // Outside the download windows only priority albums get to download
+    bulk_on_hold: bool,
+    in_flight: usize,
+    in_flight_per_host: HashMap<String, usize>,
+}
+
+impl DownloadQueue {
+    pub fn new(max_concurrent: usize, max_per_host: usize) -> Self {
+        Self {
+            albums: VecDeque::new(),
+            // A limit of zero would mean nothing ever downloads
+            max_concurrent: max_concurrent.max(1),
+            max_per_host: max_per_host.max(1),
+            paused: false,
+            bulk_on_hold: false,
+            in_flight: 0,
+            in_flight_per_host: HashMap::new(),
+        }
+    }
+
+    pub fn push_album(&mut self, album_id: u32, tracks: Vec<TrackDownload>, priority: bool) {
+        let jobs = tracks.into_iter().map(|track| TrackJob {
+            album_id,
+            index: track.index,
+            host: host_of(&track.download_url),
+            download_url: track.download_url,
+            file_path: track.file_path,
+            tags: track.tags,
+        });
+
+        match self.albums.iter_mut().find(|a| a.album_id == album_id) {
+            Some(album) => {
+                album.pending.extend(jobs);
+                album.priority |= priority;
+            }
+            None => self.albums.push_back(AlbumJob {
+                album_id,
+                priority,
+                pending: jobs.collect(),
+                in_flight: Vec::new(),
+                finished: Vec::new(),
+                failures: Vec::new(),
+                reserved_bytes: 0,
+            }),
+        }
+    }
+
+    // Sets aside disk space for a queued album until it's done or cancelled
+    pub fn reserve(&mut self, album_id: u32, bytes: u64) {
+        if let Some(album) = self.albums.iter_mut().find(|a| a.album_id == album_id) {
+            album.reserved_bytes += bytes;
+        }
+    }
+
+    // Disk space the queued albums are expected to need.
+    // Tracks that already finished are counted here as well as on disk, which errs on the safe side.
+    pub fn reserved_bytes(&self) -> u64 {
+        self.albums.iter().map(|album| album.reserved_bytes).sum()
+    }
+
+    // Returns false if the album isn't queued (anymore)
+    pub fn prioritise(&mut self, album_id: u32) -> bool {
+        self.albums
+            .iter_mut()
+            .find(|album| album.album_id == album_id)
+            .map(|album| album.priority = true)
+            .is_some()
+    }
+
+    pub fn next_track(&mut self) -> Option<TrackJob> {
+        if self.paused || self.in_flight >= self.max_concurrent {
+            return None;
+        }
+
+        let has_capacity = |album: &AlbumJob| {
+            album
+                .pending
+                .front()
+                .is_some_and(|track| self.host_has_capacity(&track.host))
+        };
+        let index = self
+            .albums
+            .iter()
+            .position(|album| album.priority && has_capacity(album))
+            .or_else(|| match self.bulk_on_hold {
+                true => None,
+                false => self.albums.iter().position(has_capacity),
+            })?;
+
+        // The album that got a turn goes to the back of the line
+        let mut album = self.albums.remove(index)?;
+        let track = album.pending.pop_front()?;
+        album.in_flight.push(InFlight {
+            track: track.clone(),
+            abort_handle: None,
+        });
+        self.albums.push_back(album);
+
+        self.in_flight += 1;
+        *self
+            .in_flight_per_host
+            .entry(track.host.clone())
+            .or_default() += 1;
+
+        Some(track)
+    }
+
+    // Lets cancel and pause stop a track that is already downloading
+    pub fn set_abort_handle(&mut self, track: &TrackJob, abort_handle: AbortHandle) {
+        if let Some(in_flight) = self.find_in_flight(track) {
+            in_flight.abort_handle = Some(abort_handle);
+        }
+    }
+
+    // Returns the album's outcome once its last track is finished
+    pub fn finish_track(
+        &mut self,
+        track: TrackJob,
+        result: Result<(), TrackFailure>,
+    ) -> Option<(u32, Result<(), Vec<TrackFailure>>)> {
+        // Tracks of cancelled albums and paused tracks were already released
+        self.find_in_flight(&track)?;
+        self.release(&track);
+
+        let index = self
+            .albums
+            .iter()
+            .position(|album| album.album_id == track.album_id)?;
+        let album = &mut self.albums[index];
+        album.in_flight.retain(|f| f.track.index != track.index);
+        album.finished.push((track.index, result.is_ok()));
+        if let Err(err) = result {
+            album.failures.push(err);
+        }
+
+        if album.in_flight.is_empty() && album.pending.is_empty() {
+            let album = self.albums.remove(index)?;
+            let outcome = match album.failures.is_empty() {
+                true => Ok(()),
+                false => Err(album.failures),
+            };
+            Some((album.album_id, outcome))
+        } else {
+            None
+        }
+    }
+
+    // Returns the handles of the album's tracks that are downloading right now, which need aborting
+    pub fn cancel_album(&mut self, album_id: u32) -> Vec<AbortHandle> {
+        let Some(index) = self.albums.iter().position(|a| a.album_id == album_id) else {
+            return Vec::new();
+        };
+        let album = self.albums.remove(index).unwrap();
+        self.release_all(album.in_flight)
+    }
+
+    pub fn cancel_all(&mut self) -> Vec<AbortHandle> {
+        let in_flight = self
+            .albums
+            .drain(..)
+            .flat_map(|album| album.in_flight)
+            .collect();
+        self.release_all(in_flight)
+    }
+
+    // Puts the tracks that are downloading back in line (their .part files let them resume later)
+    // and returns their handles, which need aborting
+    pub fn pause(&mut self) -> Vec<AbortHandle> {
+        self.paused = true;
+
+        let mut in_flight = Vec::new();
+        for album in self.albums.iter_mut() {
+            for f in album.in_flight.drain(..) {
+                album.pending.push_front(f.track.clone());
+                in_flight.push(f);
+            }
+        }
+        self.release_all(in_flight)
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Tracks already downloading carry on, but no new bulk tracks are started
+    pub fn hold_bulk(&mut self, hold: bool) {
+        self.bulk_on_hold = hold;
+    }
+
+    // Whether there are albums waiting for the hold to be lifted
+    pub fn is_bulk_on_hold(&self) -> bool {
+        self.bulk_on_hold && self.albums.iter().any(|album| !album.priority)
+    }
+
+    pub fn snapshot(&self) -> Vec<QueuedAlbum> {
+        self.albums
+            .iter()
+            .map(|album| {
+                let finished = album.finished.iter().map(|(index, ok)| QueuedTrack {
+                    index: *index,
+                    state: match ok {
+                        true => TrackState::Downloaded,
+                        false => TrackState::Failed,
+                    },
+                });
+                let in_flight = album.in_flight.iter().map(|f| QueuedTrack {
+                    index: f.track.index,
+                    state: TrackState::Downloading,
+                });
+                let pending = album.pending.iter().map(|track| QueuedTrack {
+                    index: track.index,
+                    state: TrackState::Queued,
+                });
+
+                let mut tracks = finished.chain(in_flight).chain(pending).collect::<Vec<_>>();
+                tracks.sort_by_key(|track| track.index);
+
+                QueuedAlbum {
+                    album_id: album.album_id,
+                    priority: album.priority,
+                    tracks,
+                }
+            })
+            .collect()
+    }
+
+    fn find_in_flight(&mut self, track: &TrackJob) -> Option<&mut InFlight> {
+        self.albums
+            .iter_mut()
+            .find(|album| album.album_id == track.album_id)?
+            .in_flight
+            .iter_mut()
+            .find(|f| f.track.index == track.index)
+    }
+
+    fn release(&mut self, track: &TrackJob) {
+        self.in_flight -= 1;
+        if let Some(count) = self.in_flight_per_host.get_mut(&track.host) {
+            *count -= 1;
+        }
+    }
+
+    fn release_all(&mut self, in_flight: Vec<InFlight>) -> Vec<AbortHandle> {
+        in_flight
+            .into_iter()
+            .filter_map(|f| {
+                self.release(&f.track);
+                f.abort_handle
+            })
+            .collect()
+    }
+
+    fn host_has_capacity(&self, host: &str) -> bool {
+        self.in_flight_per_host.get(host).copied().unwrap_or(0) < self.max_per_host
+    }
+}
+
+fn host_of(download_url: &str) -> String {
+    Url::parse(download_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracks(host: &str, count: usize) -> Vec<TrackDownload> {
+        (0..count)
+            .map(|index| TrackDownload {
+                index,
+                download_url: format!("https://{host}/{index}"),
+                file_path: PathBuf::from(format!("{index}.mp3")),
+                tags: TrackTags::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn albums_take_turns() {
+        let mut queue = DownloadQueue::new(10, 10);
+        queue.push_album(1, tracks("a.com", 3), false);
+        queue.push_album(2, tracks("a.com", 3), false);
+
+        let order = std::iter::from_fn(|| queue.next_track())
+            .map(|track| track.album_id)
+            .collect::<Vec<u32>>();
+
+        assert_eq!(order, vec![1, 2, 1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn concurrency_is_limited_overall_and_per_host() {
+        let mut queue = DownloadQueue::new(3, 2);
+        queue.push_album(1, tracks("a.com", 5), false);
+        queue.push_album(2, tracks("b.com", 5), false);
+
+        let started = std::iter::from_fn(|| queue.next_track()).collect::<Vec<TrackJob>>();
+        assert_eq!(started.len(), 3);
+        assert!(queue.next_track().is_none());
+
+        let hosts = started.iter().filter(|t| t.host == "a.com").count();
+        assert_eq!(hosts, 2);
+
+        let mut started = started.into_iter();
+        queue.finish_track(started.next().unwrap(), Ok(()));
+        assert!(queue.next_track().is_some());
+    }
+
+    #[test]
+    fn album_outcome_is_reported_after_last_track() {
+        let mut queue = DownloadQueue::new(10, 10);
+        queue.push_album(1, tracks("a.com", 2), false);
+
+        let first = queue.next_track().unwrap();
+        let second = queue.next_track().unwrap();
+
+        let failure = TrackFailure {
+            index: first.index,
+            error: "410 Gone".to_owned(),
+            attempts: 1,
+        };
+        assert!(queue.finish_track(first, Err(failure)).is_none());
+        let (album_id, result) = queue.finish_track(second, Ok(())).unwrap();
+
+        assert_eq!(album_id, 1);
+        assert_eq!(result.unwrap_err()[0].index, 0);
+    }
+
+    #[test]
+    fn priority_albums_jump_the_queue() {
+        let mut queue = DownloadQueue::new(10, 10);
+        queue.push_album(1, tracks("a.com", 2), false);
+        queue.push_album(2, tracks("a.com", 2), false);
+        queue.prioritise(2);
+
+        let order = std::iter::from_fn(|| queue.next_track())
+            .map(|track| track.album_id)
+            .collect::<Vec<u32>>();
+
+        assert_eq!(order, vec![2, 2, 1, 1]);
+    }
+
+    #[test]
+    fn bulk_albums_wait_while_on_hold() {
+        let mut queue = DownloadQueue::new(10, 10);
+        queue.push_album(1, tracks("a.com", 1), false);
+        queue.push_album(2, tracks("a.com", 1), true);
+        queue.hold_bulk(true);
+
+        assert_eq!(queue.next_track().unwrap().album_id, 2);
+        assert!(queue.next_track().is_none());
+        assert!(queue.is_bulk_on_hold());
+
+        queue.hold_bulk(false);
+        assert_eq!(queue.next_track().unwrap().album_id, 1);
+    }
+
+    #[test]
+    fn pausing_requeues_tracks_in_flight() {
+        let mut queue = DownloadQueue::new(1, 1);
+        queue.push_album(1, tracks("a.com", 2), false);
+
+        let first = queue.next_track().unwrap();
+        queue.pause();
+        assert!(queue.next_track().is_none());
+        // A paused track finishing anyway doesn't count twice
+        assert!(queue.finish_track(first, Ok(())).is_none());
+
+        queue.resume();
+        assert_eq!(queue.next_track().unwrap().index, 0);
+    }
+
+    #[test]
+    fn cancelling_frees_up_slots() {
+        let mut queue = DownloadQueue::new(1, 1);
+        queue.push_album(1, tracks("a.com", 2), false);
+        queue.push_album(2, tracks("a.com", 2), false);
+
+        let first = queue.next_track().unwrap();
+        queue.cancel_album(1);
+
+        assert!(queue.finish_track(first, Ok(())).is_none());
+        assert_eq!(queue.next_track().unwrap().album_id, 2);
+    }
+
+    #[test]
+    fn snapshot_has_the_state_of_every_track() {
+        let mut queue = DownloadQueue::new(1, 1);
+        queue.push_album(1, tracks("a.com", 3), true);
+
+        let first = queue.next_track().unwrap();
+        queue.finish_track(first, Ok(()));
+        queue.next_track();
+
+        let states = queue.snapshot()[0]
+            .tracks
+            .iter()
+            .map(|track| track.state)
+            .collect::<Vec<TrackState>>();
+
+        assert_eq!(
+            states,
+            vec![
+                TrackState::Downloaded,
+                TrackState::Downloading,
+                TrackState::Queued
+            ]
+        );
+    }
+}
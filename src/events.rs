@@ -1,7 +1,23 @@
 use crossterm::event::KeyEvent;
+use std::path::PathBuf;
+
+use crate::download_queue::TrackFailure;
+use crate::stats::LibraryStats;
 
 pub enum Event {
     Input(KeyEvent),
     AlbumDownloaded(u32),
-    AlbumDownLoadFailed(u32, anyhow::Error),
+    // Album id, track index, bytes received and bytes expected (if the server said)
+    TrackDownloadProgress(u32, usize, u64, Option<u64>),
+    TrackDownloaded(u32, usize),
+    AlbumDownLoadFailed(u32, Vec<TrackFailure>),
+    PackageDownloadFailed(u32, anyhow::Error),
+    // The album id and the extras (booklets, artwork, ...) that came with the package
+    AlbumPackageDownloaded(u32, Vec<PathBuf>),
+    // Files or directories under the library root were created, removed or renamed
+    LibraryChanged(Vec<PathBuf>),
+    // The statistics asked for with 's', worked out in the background
+    StatsComputed(LibraryStats),
+    // Something went wrong in the background that the user should know about
+    Error(anyhow::Error),
 }
@@ -0,0 +1,207 @@
+use anyhow::{Result, bail};
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc;
+use tokio::runtime::Runtime;
+
+use crate::collection::{Album, Collection, DownloadStatus};
+use crate::config::Config;
+use crate::download_manager::{DownloadManager, load_queue, remove_partial_downloads};
+use crate::download_queue::TrackFailure;
+use crate::events::Event;
+
+// Exit code for when some albums couldn't be downloaded (errors that stop everything exit with 1, bad arguments with 2)
+pub const EXIT_DOWNLOADS_FAILED: u8 = 3;
+
+pub enum Selection {
+    All,
+    // Matched on the band name, ignoring case
+    Artist(String),
+    Album(u32),
+}
+
+impl Selection {
+    fn matches(&self, album: &Album) -> bool {
+        match self {
+            Selection::All => true,
+            Selection::Artist(artist) => album.band_name.to_lowercase() == artist.to_lowercase(),
+            Selection::Album(id) => album.id == *id,
+        }
+    }
+}
+
+// Downloads the selected albums (and whatever was still queued) without the TUI, printing
+// progress to stdout as it goes. Returns once every download has finished or failed.
+// A single album counts as asked for by the user, anything more is a bulk download that
+// waits for the download windows.
+pub fn download(
+    mut collection: Collection,
+    selection: Selection,
+    download_runtime: Runtime,
+    config: &Config,
+    queue_path: PathBuf,
+    json: bool,
+) -> Result<ExitCode> {
+    // Asking for an artist or album that isn't there is most likely a typo
+    if !collection
+        .albums()
+        .any(|album| album.downloadable && selection.matches(album))
+    {
+        match selection {
+            Selection::All => (),
+            Selection::Artist(artist) => bail!("No albums by \"{artist}\" in the collection"),
+            Selection::Album(id) => bail!("No album with id {id} in the collection"),
+        }
+    }
+
+    let report = Report { json };
+    let (mpsc_tx, mpsc_rx) = mpsc::channel();
+    remove_partial_downloads(&collection)?;
+
+    let queued = load_queue(&queue_path)?;
+    let download_manager = DownloadManager::new(
+        mpsc_tx,
+        download_runtime,
+        config,
+        queue_path,
+        collection.library_root().to_owned(),
+    );
+    collection.resume_downloads(queued, &download_manager);
+    match selection {
+        Selection::All => collection.download_matching(&download_manager, |_| true),
+        Selection::Artist(_) => {
+            collection.download_matching(&download_manager, |album| selection.matches(album))
+        }
+        Selection::Album(id) => {
+            let tracks = collection.album(id).map_or(0, |album| album.tracks.len());
+            collection.download_tracks(id, (0..tracks).collect(), &download_manager);
+        }
+    }
+
+    let mut pending = collection
+        .albums()
+        .filter(|album| album.download_status() == DownloadStatus::Downloading)
+        .map(|album| album.id)
+        .collect::<HashSet<u32>>();
+    report.started(pending.len());
+
+    let (mut downloaded, mut failed) = (0, 0);
+    while !pending.is_empty() {
+        match mpsc_rx.recv()? {
+            Event::TrackDownloaded(id, index) => {
+                collection.set_track_downloaded(id, index);
+                if let Some(album) = collection.album(id) {
+                    report.track_downloaded(album, index);
+                }
+            }
+            Event::AlbumDownloaded(id) if pending.remove(&id) => {
+                downloaded += 1;
+                if let Some(album) = collection.set_downloaded(id) {
+                    report.album_downloaded(album);
+                }
+            }
+            Event::AlbumDownLoadFailed(id, failures) if pending.remove(&id) => {
+                failed += 1;
+                if let Some(album) = collection.set_failed(id, failures.clone()) {
+                    report.album_failed(album, &failures);
+                }
+            }
+            Event::Error(err) => eprintln!("{err:?}"),
+            _ => (),
+        }
+    }
+    report.finished(downloaded, failed);
+
+    Ok(match failed {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_DOWNLOADS_FAILED),
+    })
+}
+
+// Prints either lines for people or JSON objects (one per line) for scripts
+struct Report {
+    json: bool,
+}
+
+impl Report {
+    fn started(&self, albums: usize) {
+        match self.json {
+            true => println!("{}", json!({ "event": "started", "albums": albums })),
+            false => println!("Downloading {albums} albums"),
+        }
+    }
+
+    fn track_downloaded(&self, album: &Album, index: usize) {
+        let Some(track) = album.tracks.get(index) else {
+            return;
+        };
+        match self.json {
+            true => println!(
+                "{}",
+                json!({
+                    "event": "track_downloaded",
+                    "album_id": album.id,
+                    "track_number": track.number,
+                    "title": track.title,
+                    "file_path": track.file_path,
+                })
+            ),
+            false => println!("  {}: {:02} - {}", album.title, track.number, track.title),
+        }
+    }
+
+    fn album_downloaded(&self, album: &Album) {
+        match self.json {
+            true => println!(
+                "{}",
+                json!({ "event": "album_downloaded", "album_id": album.id, "title": album.title })
+            ),
+            false => println!("✅ {}", album.title),
+        }
+    }
+
+    fn album_failed(&self, album: &Album, failures: &[TrackFailure]) {
+        if self.json {
+            let failures = failures
+                .iter()
+                .map(|failure| {
+                    json!({ "index": failure.index, "error": failure.error, "attempts": failure.attempts })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                json!({
+                    "event": "album_failed",
+                    "album_id": album.id,
+                    "title": album.title,
+                    "failures": failures,
+                })
+            );
+            return;
+        }
+
+        println!("🚨 {}: {} tracks failed", album.title, failures.len());
+        for failure in failures {
+            let title = album
+                .tracks
+                .get(failure.index)
+                .map_or("?", |track| track.title.as_str());
+            println!(
+                "  {title}: {} ({} attempts)",
+                failure.error, failure.attempts
+            );
+        }
+    }
+
+    fn finished(&self, downloaded: usize, failed: usize) {
+        match self.json {
+            true => println!(
+                "{}",
+                json!({ "event": "finished", "downloaded": downloaded, "failed": failed })
+            ),
+            false => println!("{downloaded} albums downloaded, {failed} failed"),
+        }
+    }
+}
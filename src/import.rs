@@ -0,0 +1,280 @@
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::collection::{Album, Collection};
+use crate::download_manager::{AUDIO_EXTENSIONS, track_number_from_file_name};
+use crate::storage::{lowercase_extension, move_file, write_through_part_file};
+
+// A file to bring into the library, either loose or in a zip
+#[derive(Debug, Clone)]
+pub enum ImportFile {
+    File(PathBuf),
+    ZipEntry { zip: PathBuf, index: usize },
+}
+
+pub struct AlbumImport {
+    pub album_title: String,
+    // Where the files came from, the folder or zip
+    pub from: PathBuf,
+    pub files: Vec<(ImportFile, PathBuf)>,
+    // Files already in the library, which are left alone
+    pub already_there: Vec<PathBuf>,
+    // Music that isn't one of the album's tracks
+    pub unmatched: Vec<String>,
+}
+
+// What importing the folders and zips would do
+#[derive(Default)]
+pub struct ImportPlan {
+    pub albums: Vec<AlbumImport>,
+    // Folders and zips that don't go with any album in the collection
+    pub unknown: Vec<PathBuf>,
+}
+
+// A folder or zip with an album in it, the way Bandcamp's download page hands them out
+struct Container {
+    path: PathBuf,
+    // The zip or folder name without extension, e.g. "Band - Album"
+    name: String,
+    // File names, and where to get each of them
+    entries: Vec<(String, ImportFile)>,
+}
+
+// Matches the folders and zips (and the ones in them) to albums in the collection by artist,
+// album and track titles. Files are named "{artist} - {album} - {nn} {title}.{ext}" by Bandcamp,
+// and the zip or folder "{artist} - {album}".
+pub fn plan(paths: &[PathBuf], collection: &Collection) -> Result<ImportPlan> {
+    let mut containers = Vec::new();
+    for path in paths {
+        find_containers(path, &mut containers)?;
+    }
+
+    let mut plan = ImportPlan::default();
+    for container in containers {
+        let audio_names = container
+            .entries
+            .iter()
+            .map(|(name, _)| name.clone())
+            .filter(|name| is_audio(name))
+            .collect::<Vec<String>>();
+
+        // Albums with the same artist and title (a deluxe edition, ...) are told apart by their tracks
+        let best = collection
+            .albums()
+            .filter(|album| album.downloadable)
+            .filter_map(|album| {
+                let matches = match_tracks(album, &container.name, &audio_names)?;
+                let score = matches.iter().flatten().count();
+                Some((album, matches, score))
+            })
+            .filter(|(_, _, score)| *score > 0)
+            .max_by_key(|(_, _, score)| *score);
+
+        match best {
+            Some((album, matches, _)) => plan.albums.push(album_import(album, container, matches)),
+            None => plan.unknown.push(container.path),
+        }
+    }
+    Ok(plan)
+}
+
+impl ImportPlan {
+    // Moves the files out of the folders and extracts them from the zips
+    pub fn apply(&self) -> Result<()> {
+        for album in &self.albums {
+            for (file, to) in &album.files {
+                match file {
+                    ImportFile::File(from) => move_file(from, to)?,
+                    ImportFile::ZipEntry { zip, index } => extract(zip, *index, to)?,
+                }
+            }
+            // Folders that had nothing else in them go
+            if album.from.is_dir() {
+                let _ = fs::remove_dir(&album.from);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn album_import(album: &Album, container: Container, matches: Vec<Option<usize>>) -> AlbumImport {
+    let mut import = AlbumImport {
+        album_title: album.title.clone(),
+        from: container.path,
+        files: Vec::new(),
+        already_there: Vec::new(),
+        unmatched: Vec::new(),
+    };
+    let album_dir = album.directory().unwrap_or_default();
+
+    let mut matches = matches.into_iter();
+    for (name, file) in container.entries {
+        let to = match is_audio(&name) {
+            true => match matches.next().flatten() {
+                // Already there, maybe in another format
+                Some(index) if album.tracks[index].file_path.exists() => {
+                    import
+                        .already_there
+                        .push(album.tracks[index].file_path.clone());
+                    continue;
+                }
+                // Keeps the format it came in, see BandcampSource::track_locations
+                Some(index) => album.tracks[index]
+                    .file_path
+                    .with_extension(lowercase_extension(Path::new(&name))),
+                None => {
+                    import.unmatched.push(name);
+                    continue;
+                }
+            },
+            false => album_dir.join(&name),
+        };
+
+        match to.exists() {
+            true => import.already_there.push(to),
+            false => import.files.push((file, to)),
+        }
+    }
+    import
+}
+
+// The track index for each of the audio files, if the files are the album's. None when
+// the container is for another album.
+fn match_tracks(
+    album: &Album,
+    container_name: &str,
+    audio_names: &[String],
+) -> Option<Vec<Option<usize>>> {
+    let album_name = normalise(&format!("{}{}", album.tags.artist, album.tags.album));
+    let named_for_album = normalise(container_name) == album_name
+        || audio_names
+            .iter()
+            .any(|name| normalise(name).starts_with(&album_name));
+    if !named_for_album {
+        return None;
+    }
+
+    let matches = audio_names
+        .iter()
+        .map(|name| {
+            let number = track_number_from_file_name(Path::new(name))?;
+            let stem = normalise(Path::new(name).file_stem()?.to_str()?);
+            album.tracks.iter().position(|track| {
+                track.number == number && stem.ends_with(&normalise(&track.title))
+            })
+        })
+        .collect();
+    Some(matches)
+}
+
+fn find_containers(path: &Path, containers: &mut Vec<Container>) -> Result<()> {
+    let name = path
+        .file_stem()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+
+    if path.is_file() && lowercase_extension(path) == "zip" {
+        let archive = zip::ZipArchive::new(File::open(path)?)?;
+        // Bandcamp zips are flat, anything in subfolders goes under its own name
+        let entries = (0..archive.len())
+            .filter_map(|index| {
+                let name = archive.name_for_index(index)?.ok()?;
+                let file_name = Path::new(name.as_ref())
+                    .file_name()?
+                    .to_string_lossy()
+                    .into_owned();
+                let zip = path.to_owned();
+                (!name.ends_with('/')).then_some((file_name, ImportFile::ZipEntry { zip, index }))
+            })
+            .collect();
+        containers.push(Container {
+            path: path.to_owned(),
+            name,
+            entries,
+        });
+    } else if path.is_dir() {
+        let mut entries = Vec::new();
+        let mut paths = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        paths.sort();
+        for child in paths {
+            if child.is_dir() || lowercase_extension(&child) == "zip" {
+                find_containers(&child, containers)?;
+            } else if let Some(file_name) = child.file_name() {
+                let file_name = file_name.to_string_lossy().into_owned();
+                entries.push((file_name, ImportFile::File(child)));
+            }
+        }
+
+        if entries.iter().any(|(name, _)| is_audio(name)) {
+            containers.push(Container {
+                path: path.to_owned(),
+                name,
+                entries,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn extract(zip: &Path, index: usize, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut archive = zip::ZipArchive::new(File::open(zip)?)?;
+    write_through_part_file(to, archive.by_index(index)?)
+}
+
+fn is_audio(name: &str) -> bool {
+    AUDIO_EXTENSIONS.contains(&lowercase_extension(Path::new(name)).as_str())
+}
+
+// Bandcamp's file names leave out or replace characters, so only letters and digits are compared
+fn normalise(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{SourceAlbum, SourceTrack};
+
+    #[test]
+    fn bandcamp_downloads_are_matched_by_name() {
+        let track = |number: u8, title: &str| SourceTrack {
+            number,
+            title: title.to_owned(),
+            duration: None,
+        };
+        let source_album = SourceAlbum {
+            artist: "Band".to_owned(),
+            title: "Album: Live?".to_owned(),
+            tracks: vec![track(1, "Intro"), track(2, "Song/Other")],
+            ..SourceAlbum::default()
+        };
+        let album = Album::new(
+            source_album,
+            vec![PathBuf::from("1.mp3"), PathBuf::from("2.mp3")],
+            vec![Some(String::new()), Some(String::new())],
+        );
+
+        let names = [
+            "Band - Album Live - 02 SongOther.flac".to_owned(),
+            "Band - Album Live - 01 Intro.flac".to_owned(),
+            "Band - Album Live - 03 Bonus.flac".to_owned(),
+        ];
+        assert_eq!(
+            match_tracks(&album, "whatever", &names),
+            Some(vec![Some(1), Some(0), None])
+        );
+        assert_eq!(
+            match_tracks(&album, "Other Band - Album", &names[..0]),
+            None
+        );
+    }
+}
@@ -1,8 +1,9 @@
 use anyhow::Result;
 use serde::{Serialize, de::DeserializeOwned};
 use std::io::Write;
+use std::path::Path;
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{BufRead, BufReader},
 };
 
@@ -16,7 +17,7 @@ pub fn read_lines_from_file<T: DeserializeOwned>(file: File) -> Result<Vec<T>> {
 }
 
 pub fn write_lines_to_file<'a, T: Serialize + 'a>(
-    mut file: File,
+    mut file: impl Write,
     mut items: impl Iterator<Item = &'a T>,
 ) -> Result<()> {
     items.try_for_each(|item| {
@@ -27,6 +28,24 @@ pub fn write_lines_to_file<'a, T: Serialize + 'a>(
     Ok(())
 }
 
+// Writes the items next to path first, then moves them into place, so a crash (or power cut)
+// halfway through leaves the old file as it was instead of half a file
+pub fn replace_file_lines<'a, T: Serialize + 'a>(
+    path: &Path,
+    items: impl Iterator<Item = &'a T>,
+) -> Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let file = File::create(&temp_path)?;
+    write_lines_to_file(&file, items)?;
+    // Otherwise the rename can reach the disk before the contents do
+    file.sync_all()?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs::remove_file, path::PathBuf, str::FromStr};
@@ -60,4 +79,23 @@ mod tests {
 
         assert_eq!(items, items_from_file);
     }
+
+    #[test]
+    fn replaced_files_are_swapped_in_whole() {
+        let file_path = PathBuf::from_str("test-replace.jsonl").unwrap();
+        let items = vec![TestStruct {
+            field: "new".to_owned(),
+        }];
+
+        replace_file_lines(&file_path, items.iter()).unwrap();
+
+        let items_from_file = read_lines_from_file(File::open(&file_path).unwrap()).unwrap();
+        assert_eq!(items, items_from_file);
+        assert!(
+            !PathBuf::from_str("test-replace.jsonl.tmp")
+                .unwrap()
+                .exists()
+        );
+        remove_file(&file_path).unwrap();
+    }
 }
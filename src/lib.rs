@@ -1,7 +1,25 @@
+pub mod album_detail;
 pub mod app;
 pub mod bandcamp;
+pub mod cache;
 pub mod collection;
+pub mod config;
+pub mod delete_confirmation;
 pub mod download_manager;
+pub mod download_queue;
 pub mod events;
+pub mod headless;
+pub mod import;
 pub mod json_l;
+pub mod library;
+pub mod local;
 pub mod player;
+pub mod progress;
+pub mod relocate;
+pub mod source;
+pub mod stats;
+pub mod storage;
+pub mod tags;
+pub mod throttle;
+pub mod verify;
+pub mod watcher;
@@ -0,0 +1,356 @@
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::Config;
+use crate::download_manager::PART_EXTENSION;
+
+// The layout the library always had: bandcamp/Band/Album/01 - Title.mp3
+pub const DEFAULT_TEMPLATE: &str = "{artist}/{album}/{track:02} - {title}.{ext}";
+
+// Most file systems allow 255 bytes for a name, and downloads get ".part" added while they run
+const MAX_NAME_BYTES: usize = 255 - PART_EXTENSION.len();
+
+// Characters that FAT/exFAT, NTFS and SMB shares don't allow in names (and the path separators)
+const RESERVED_CHARS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+// Device names Windows won't let a file be called, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Turns a band name, album title or track title into a name that works on any file system the
+// library might end up on (or be synced to). The rules, in order:
+// 1. Unicode is normalised to NFC, so the same title always comes out as the same bytes
+// 2. Control characters are dropped
+// 3. / \ : * ? " < > | are replaced with _
+// 4. Whitespace at either end and dots at the end are trimmed
+// 5. Windows device names (CON, NUL, COM1, ...) get a _ appended
+// 6. Nothing left at all becomes _
+// Names that are too long are cut short by PathTemplate::render, which knows what else goes
+// in the same path component.
+pub fn sanitise(name: &str) -> String {
+    let replaced = name
+        .nfc()
+        .filter(|c| !c.is_control())
+        .map(|c| match RESERVED_CHARS.contains(&c) {
+            true => '_',
+            false => c,
+        })
+        .collect::<String>();
+
+    let mut sanitised = trim(&replaced).to_owned();
+
+    let stem = sanitised.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        sanitised.insert(stem.len(), '_');
+    }
+
+    match sanitised.is_empty() {
+        true => "_".to_owned(),
+        false => sanitised,
+    }
+}
+
+fn trim(name: &str) -> &str {
+    name.trim().trim_end_matches(['.', ' '])
+}
+
+// Finds the names that would end up the same on disk. All but the one with the lowest id clash,
+// and need telling apart (e.g. with a " [id]" suffix). Names that only differ in case count as the
+// same, for the sake of case insensitive file systems. Returns whether each name clashes, in order.
+pub fn clashes(names: &[(u32, String)]) -> Vec<bool> {
+    let mut lowest_ids = HashMap::new();
+    for (id, name) in names {
+        lowest_ids
+            .entry(name.to_lowercase())
+            .and_modify(|lowest: &mut u32| *lowest = (*lowest).min(*id))
+            .or_insert(*id);
+    }
+
+    names
+        .iter()
+        .map(|(id, name)| lowest_ids[&name.to_lowercase()] != *id)
+        .collect()
+}
+
+// Where downloads go (an absolute path) and what they're called
+#[derive(Debug, Clone)]
+pub struct LibraryLayout {
+    pub root: PathBuf,
+    pub template: PathTemplate,
+}
+
+impl LibraryLayout {
+    pub fn new(root: &Path, template: &str) -> Result<Self> {
+        Ok(Self {
+            root: std::path::absolute(root)?,
+            template: template.parse()?,
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Self::new(&config.library_root, &config.path_template)
+    }
+
+    pub fn track_path(&self, values: &TrackValues) -> PathBuf {
+        self.root.join(self.template.render(values))
+    }
+
+    // Libraries laid out the default way may have albums from before names were sanitised
+    pub fn is_default(&self) -> bool {
+        self.template.source == DEFAULT_TEMPLATE
+    }
+}
+
+// What goes into a track's path. Bandcamp has no discs, so {disc} is always 1.
+pub struct TrackValues<'a> {
+    pub artist: &'a str,
+    pub album: &'a str,
+    // Left empty when it isn't known
+    pub year: Option<&'a str>,
+    pub track: u8,
+    pub title: &'a str,
+    // Tell albums and tracks whose names clash apart, e.g. " [1234]" (see clashes). They go
+    // in after names are cut short, so they're never lost.
+    pub album_suffix: &'a str,
+    pub title_suffix: &'a str,
+    pub ext: &'a str,
+}
+
+// A path relative to the library root, with placeholders for the track's details, e.g.
+// "{artist}/{album} ({year})/{track:02} - {title}.{ext}". A number after the colon pads numbers
+// with zeros to that width. Values are sanitised (see sanitise) before they go in, and "/" is
+// the only thing that makes directories.
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    source: String,
+    components: Vec<Vec<Part>>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Text(String),
+    Field(Field, usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Artist,
+    Album,
+    Year,
+    Disc,
+    Track,
+    Title,
+    Ext,
+}
+
+impl FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let components = source
+            .split('/')
+            .map(|component| match component {
+                "" | "." | ".." => Err(anyhow!(
+                    "Path template \"{source}\" has to stay inside the library, without empty, . or .. directories"
+                )),
+                component => parse_component(component),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            source: source.to_owned(),
+            components,
+        })
+    }
+}
+
+fn parse_component(component: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].to_owned()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed {{ in path template"))?
+            + start;
+
+        let placeholder = &rest[start + 1..end];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => (name, width.parse::<usize>()?),
+            None => (placeholder, 0),
+        };
+        let field = match name {
+            "artist" => Field::Artist,
+            "album" => Field::Album,
+            "year" => Field::Year,
+            "disc" => Field::Disc,
+            "track" => Field::Track,
+            "title" => Field::Title,
+            "ext" => Field::Ext,
+            _ => {
+                return Err(anyhow!(
+                    "Unknown placeholder {{{placeholder}}} in path template"
+                ));
+            }
+        };
+        parts.push(Part::Field(field, width));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest.to_owned()));
+    }
+    Ok(parts)
+}
+
+impl PathTemplate {
+    pub fn render(&self, values: &TrackValues) -> PathBuf {
+        self.components
+            .iter()
+            .map(|parts| render_component(parts, values))
+            .collect()
+    }
+}
+
+// Names that would make the component longer than MAX_NAME_BYTES are cut short, the longest one
+// first, so the template's text, numbers, suffixes and the extension always make it in
+fn render_component(parts: &[Part], values: &TrackValues) -> String {
+    // The names that can be cut short, and what goes after each of them as is
+    let mut pieces = parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => (String::new(), text.clone()),
+            Part::Field(Field::Track, width) => {
+                (String::new(), format!("{:0width$}", values.track))
+            }
+            Part::Field(Field::Disc, width) => (String::new(), format!("{:0width$}", 1)),
+            Part::Field(Field::Artist, _) => (sanitise(values.artist), String::new()),
+            Part::Field(Field::Album, _) => {
+                (sanitise(values.album), values.album_suffix.to_owned())
+            }
+            Part::Field(Field::Title, _) => {
+                (sanitise(values.title), values.title_suffix.to_owned())
+            }
+            Part::Field(Field::Year, _) => {
+                (values.year.map(sanitise).unwrap_or_default(), String::new())
+            }
+            Part::Field(Field::Ext, _) => (String::new(), sanitise(values.ext)),
+        })
+        .collect::<Vec<(String, String)>>();
+
+    let length = |pieces: &[(String, String)]| {
+        pieces
+            .iter()
+            .map(|(name, rest)| name.len() + rest.len())
+            .sum::<usize>()
+    };
+    while length(&pieces) > MAX_NAME_BYTES {
+        let Some((longest, _)) = pieces
+            .iter_mut()
+            .max_by_key(|(name, _)| name.len())
+            .filter(|(name, _)| !name.is_empty())
+        else {
+            break;
+        };
+        longest.pop();
+        *longest = trim(longest).to_owned();
+    }
+
+    let component = pieces
+        .into_iter()
+        .map(|(name, rest)| name + &rest)
+        .collect::<String>();
+    // e.g. "{album} {year}" without a year
+    match trim(&component) {
+        "" => "_".to_owned(),
+        trimmed => trimmed.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_safe_everywhere() {
+        assert_eq!(sanitise("tempor / jester"), "tempor _ jester");
+        assert_eq!(sanitise("What? Now: *This*"), "What_ Now_ _This_");
+        assert_eq!(sanitise("Tab\tand\nnewline"), "Tabandnewline");
+        assert_eq!(sanitise(" Trailing... "), "Trailing");
+        assert_eq!(sanitise("con"), "con_");
+        assert_eq!(sanitise("Aux.Live"), "Aux_.Live");
+        assert_eq!(sanitise(".."), "_");
+        // "é" as e + combining accent comes out as the single character
+        assert_eq!(sanitise("Cafe\u{301}"), "Caf\u{e9}");
+    }
+
+    #[test]
+    fn clashing_names_are_found() {
+        let names = vec![
+            (7, "Live_".to_owned()),
+            (3, "live_".to_owned()),
+            (5, "Other".to_owned()),
+        ];
+
+        assert_eq!(clashes(&names), vec![true, false, false]);
+    }
+
+    #[test]
+    fn templates_fill_in_the_track() {
+        let values = TrackValues {
+            artist: "AC/DC",
+            album: "Live",
+            year: None,
+            track: 3,
+            title: "Thunder?",
+            album_suffix: "",
+            title_suffix: "",
+            ext: "mp3",
+        };
+        let render = |template: &str| {
+            template
+                .parse::<PathTemplate>()
+                .unwrap()
+                .render(&values)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        assert_eq!(render(DEFAULT_TEMPLATE), "AC_DC/Live/03 - Thunder_.mp3");
+        assert_eq!(
+            render("{artist} - {album} {year}/{disc}-{track:03}.{ext}"),
+            "AC_DC - Live/1-003.mp3"
+        );
+        assert!("{artist}/{genre}".parse::<PathTemplate>().is_err());
+
+        // Long names are cut short, but not the suffix and extension
+        let long = "é".repeat(200);
+        let values = TrackValues {
+            artist: &long,
+            album: &long,
+            album_suffix: " [42]",
+            title: &long,
+            ..values
+        };
+        let path = "{artist} - {album}/{track:02} {title}.{ext}"
+            .parse::<PathTemplate>()
+            .unwrap()
+            .render(&values);
+        for component in path.iter().map(|c| c.to_string_lossy()) {
+            assert!(component.len() <= MAX_NAME_BYTES, "{component}");
+        }
+        let directory = path.iter().next().unwrap().to_string_lossy();
+        assert!(directory.ends_with("é [42]"));
+        assert!(path.to_string_lossy().ends_with("é.mp3"));
+        assert!("../{artist}".parse::<PathTemplate>().is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::{Hint, ProbeResult};
+
+use crate::download_manager::part_path;
+use crate::source::{MusicSource, SourceAlbum, SourceTrack, year_of};
+use crate::storage::lowercase_extension;
+
+// What the scan picks up (and the player can play)
+const EXTENSIONS: [&str; 5] = ["mp3", "flac", "ogg", "oga", "m4a"];
+
+// Music from elsewhere (CD rips, other stores, ...) in local folders. There's nothing to
+// download, and nothing in the folders gets changed.
+pub struct LocalSource {
+    directories: Vec<PathBuf>,
+    albums: Vec<LocalAlbum>,
+    // Folders that weren't there (an unplugged drive, ...) or couldn't be read at the last scan
+    skipped_directories: Vec<PathBuf>,
+}
+
+impl LocalSource {
+    // Has no albums until it's synced
+    pub fn new(directories: Vec<PathBuf>) -> Self {
+        Self {
+            directories,
+            albums: Vec::new(),
+            skipped_directories: Vec::new(),
+        }
+    }
+
+    pub fn skipped_directories(&self) -> &[PathBuf] {
+        &self.skipped_directories
+    }
+}
+
+impl MusicSource for LocalSource {
+    fn albums(&self) -> Vec<SourceAlbum> {
+        self.albums
+            .iter()
+            .map(|album| SourceAlbum {
+                id: album.id,
+                artist: album.artist.clone(),
+                title: album.title.clone(),
+                year: album.year.as_deref().and_then(year_of).map(str::to_owned),
+                tracks: album
+                    .tracks
+                    .iter()
+                    .map(|track| SourceTrack {
+                        number: track.number,
+                        title: track.title.clone(),
+                        duration: track.duration,
+                    })
+                    .collect(),
+                ..SourceAlbum::default()
+            })
+            .collect()
+    }
+
+    fn track_locations(&self, album: &SourceAlbum) -> Vec<PathBuf> {
+        self.albums
+            .iter()
+            .find(|local| local.id == album.id)
+            .map_or(Vec::new(), |local| {
+                local.tracks.iter().map(|t| t.file_path.clone()).collect()
+            })
+    }
+
+    fn download_url(&self, _album: &SourceAlbum, _index: usize) -> Option<String> {
+        None
+    }
+
+    // Scans the folders again. Folders that aren't there or can't be read are skipped, see
+    // skipped_directories.
+    fn sync(&mut self) -> Result<()> {
+        (self.albums, self.skipped_directories) = scan(&self.directories);
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct LocalAlbum {
+    // Made from the artist and title, so it stays the same from one scan to the next
+    id: u32,
+    artist: String,
+    title: String,
+    year: Option<String>,
+    tracks: Vec<LocalTrack>,
+}
+
+#[derive(Debug, PartialEq)]
+struct LocalTrack {
+    number: u8,
+    title: String,
+    duration: Option<Duration>,
+    file_path: PathBuf,
+}
+
+#[derive(Debug, Default)]
+struct FileTags {
+    artist: Option<String>,
+    album_artist: Option<String>,
+    album: Option<String>,
+    title: Option<String>,
+    number: Option<u8>,
+    disc: Option<u8>,
+    year: Option<String>,
+    // Not a tag, but it's read along with them
+    duration: Option<Duration>,
+}
+
+// Reads the tags of the music files in the directories (and the directories in them),
+// and groups them into albums. Also returns the directories that couldn't be read.
+fn scan(directories: &[PathBuf]) -> (Vec<LocalAlbum>, Vec<PathBuf>) {
+    let mut files = Vec::new();
+    let mut skipped = Vec::new();
+    for directory in directories {
+        find_music(directory, &mut files, &mut skipped);
+    }
+
+    let tagged = files
+        .into_iter()
+        .map(|path| {
+            let tags = read_tags(&path).unwrap_or_default();
+            (path, tags)
+        })
+        .collect();
+    (group(tagged), skipped)
+}
+
+// Symlinked directories aren't followed, they could lead round in circles
+fn find_music(directory: &Path, files: &mut Vec<PathBuf>, skipped: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        skipped.push(directory.to_owned());
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let extension = lowercase_extension(&path);
+
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            find_music(&path, files, skipped);
+        } else if EXTENSIONS.contains(&extension.as_str()) && !part_path(&path).exists() {
+            files.push(path);
+        }
+    }
+}
+
+// Tracks go together when they have the same album artist (or artist) and album title, whatever
+// their case. Files without an album tag are grouped by the directory they're in, and named after it.
+fn group(files: Vec<(PathBuf, FileTags)>) -> Vec<LocalAlbum> {
+    let mut albums = BTreeMap::<(String, String), (String, String, Vec<_>)>::new();
+    for (path, tags) in files {
+        let artist = tags
+            .album_artist
+            .clone()
+            .or(tags.artist.clone())
+            .unwrap_or_else(|| "Unknown artist".to_owned());
+        let title = tags.album.clone().unwrap_or_else(|| {
+            path.parent()
+                .and_then(|dir| dir.file_name())
+                .map_or("Unknown album".to_owned(), |dir| {
+                    dir.to_string_lossy().into_owned()
+                })
+        });
+
+        let key = (artist.to_lowercase(), title.to_lowercase());
+        albums
+            .entry(key)
+            .or_insert_with(|| (artist, title, Vec::new()))
+            .2
+            .push((path, tags));
+    }
+
+    albums
+        .into_values()
+        .map(|(artist, title, mut files)| {
+            files.sort_by(|(a_path, a), (b_path, b)| {
+                (a.disc, a.number, a_path).cmp(&(b.disc, b.number, b_path))
+            });
+            let year = files.iter().find_map(|(_, tags)| tags.year.clone());
+            let tracks = files
+                .into_iter()
+                .enumerate()
+                .map(|(index, (file_path, tags))| LocalTrack {
+                    // Untagged tracks are numbered in the order they're in
+                    number: tags
+                        .number
+                        .unwrap_or_else(|| (index + 1).try_into().unwrap_or(u8::MAX)),
+                    title: tags.title.unwrap_or_else(|| {
+                        file_path
+                            .file_stem()
+                            .map_or(String::new(), |stem| stem.to_string_lossy().into_owned())
+                    }),
+                    duration: tags.duration,
+                    file_path,
+                })
+                .collect();
+
+            LocalAlbum {
+                id: album_id(&artist, &title),
+                artist,
+                title,
+                year,
+                tracks,
+            }
+        })
+        .collect()
+}
+
+fn album_id(artist: &str, title: &str) -> u32 {
+    let mut hasher = Sha1::new();
+    hasher.update(artist.to_lowercase());
+    hasher.update([0]);
+    hasher.update(title.to_lowercase());
+    let hash = hasher.finalize();
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+fn read_tags(path: &Path) -> Option<FileTags> {
+    let mut probed = probe(path)?;
+
+    let mut tags = FileTags {
+        duration: duration_of(probed.format.as_ref()),
+        ..FileTags::default()
+    };
+    // ID3 tags in front of the audio are read by the probe, the container's own tags
+    // (FLAC, Ogg, MP4) by the format reader
+    if let Some(metadata) = probed.metadata.get()
+        && let Some(revision) = metadata.current()
+    {
+        revision.tags().iter().for_each(|tag| tags.add(tag));
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        revision.tags().iter().for_each(|tag| tags.add(tag));
+    }
+    Some(tags)
+}
+
+// For music files whose source doesn't say how long they are
+pub(crate) fn read_duration(path: &Path) -> Option<Duration> {
+    duration_of(probe(path)?.format.as_ref())
+}
+
+fn probe(path: &Path) -> Option<ProbeResult> {
+    let stream = MediaSourceStream::new(Box::new(File::open(path).ok()?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()
+}
+
+// From the headers, without decoding anything
+fn duration_of(format: &dyn FormatReader) -> Option<Duration> {
+    let params = &format.default_track()?.codec_params;
+    let seconds = params.n_frames? as f64 / f64::from(params.sample_rate?);
+    Some(Duration::from_secs_f64(seconds))
+}
+
+impl FileTags {
+    fn add(&mut self, tag: &Tag) {
+        let value = tag.value.to_string().trim().to_owned();
+        if value.is_empty() {
+            return;
+        }
+        // Numbers can come as "3/12"
+        let number = || value.split('/').next()?.trim().parse::<u8>().ok();
+
+        match tag.std_key {
+            Some(StandardTagKey::Artist) => self.artist = Some(value),
+            Some(StandardTagKey::AlbumArtist) => self.album_artist = Some(value),
+            Some(StandardTagKey::Album) => self.album = Some(value),
+            Some(StandardTagKey::TrackTitle) => self.title = Some(value),
+            Some(StandardTagKey::TrackNumber) => self.number = number(),
+            Some(StandardTagKey::DiscNumber) => self.disc = number(),
+            Some(StandardTagKey::Date | StandardTagKey::ReleaseDate) => {
+                self.year = self.year.take().or(Some(value))
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_are_grouped_into_albums() {
+        let tagged = |path: &str, album: &str, number: u8| {
+            let tags = FileTags {
+                artist: Some("Band".to_owned()),
+                album: Some(album.to_owned()),
+                title: Some(format!("Song {number}")),
+                number: Some(number),
+                ..FileTags::default()
+            };
+            (PathBuf::from(path), tags)
+        };
+        let files = vec![
+            tagged("rips/b/02.flac", "Album", 2),
+            tagged("rips/b/01.flac", "Album", 1),
+            tagged("rips/other/03.mp3", "ALBUM", 3),
+            (PathBuf::from("rips/untagged/a.mp3"), FileTags::default()),
+            (PathBuf::from("rips/untagged/b.mp3"), FileTags::default()),
+        ];
+
+        let albums = group(files);
+
+        assert_eq!(albums.len(), 2);
+        let album = albums.iter().find(|a| a.title == "Album").unwrap();
+        assert_eq!(album.id, album_id("band", "ALBUM"));
+        let numbers = album.tracks.iter().map(|t| t.number).collect::<Vec<u8>>();
+        assert_eq!(numbers, [1, 2, 3]);
+
+        let untagged = albums.iter().find(|a| a.title == "untagged").unwrap();
+        assert_eq!(untagged.artist, "Unknown artist");
+        let titles = untagged.tracks.iter().map(|t| &t.title).collect::<Vec<_>>();
+        assert_eq!(titles, ["a", "b"]);
+        assert_eq!(untagged.tracks[1].number, 2);
+    }
+}
@@ -1,40 +1,207 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use clap::{Parser, Subcommand};
 use ratatui::prelude::*;
 use rodio::OutputStreamBuilder;
 use rusty_piano::app::*;
-use rusty_piano::bandcamp::{BandCampClient, Item};
-use rusty_piano::json_l::{read_lines_from_file, write_lines_to_file};
-use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use rusty_piano::bandcamp::BandcampSource;
+use rusty_piano::collection::Collection;
+use rusty_piano::config::Config;
+use rusty_piano::headless::{self, Selection};
+use rusty_piano::import;
+use rusty_piano::library::LibraryLayout;
+use rusty_piano::local::LocalSource;
+use rusty_piano::progress::format_bytes;
+use rusty_piano::relocate;
+use rusty_piano::source::MusicSource;
+use rusty_piano::stats::LibraryStats;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::str::FromStr;
 use std::thread;
 use std::time::Duration;
+use tokio::runtime::Runtime;
 
-fn main() -> Result<()> {
+#[derive(Parser)]
+#[command(version, about = "Plays (and downloads) your Bandcamp collection")]
+struct Args {
+    /// Check the downloaded tracks before starting, so damaged ones get downloaded again
+    #[arg(long)]
+    verify: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the downloaded tracks and report the damaged ones (exits with 1 if there are any)
+    Doctor {
+        /// Delete the damaged tracks, so the next download gets them again
+        #[arg(long)]
+        remove_damaged: bool,
+    },
+    /// Download albums without the TUI (exits with 3 if any of them fail)
+    Download {
+        #[command(flatten)]
+        selection: SelectionArgs,
+        /// Print progress as JSON, one object per line
+        #[arg(long)]
+        json: bool,
+    },
+    /// Refresh the collection from Bandcamp, then download everything that isn't downloaded yet.
+    /// Logs in with BANDCAMP_USERNAME and BANDCAMP_PASSWORD when they're set.
+    Sync {
+        /// Print progress as JSON, one object per line
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move downloaded files from an old library root or path template to the ones in config.json
+    /// (exits with 1 if some files couldn't move)
+    Relocate {
+        /// Where the library was (defaults to library_root in config.json)
+        #[arg(long)]
+        from_root: Option<PathBuf>,
+        /// How the library was laid out (defaults to path_template in config.json)
+        #[arg(long)]
+        from_template: Option<String>,
+        /// Only show what would move
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show album and track counts, listening time and disk usage
+    Stats {
+        /// Print the statistics as JSON instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move Bandcamp ZIPs and download folders into the library, so their albums count as
+    /// downloaded (exits with 1 if some of them don't match an album in the collection)
+    Import {
+        /// ZIPs, or folders with albums (or ZIPs) in them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Only show what would be imported
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(clap::Args)]
+#[group(required = true, multiple = false)]
+struct SelectionArgs {
+    /// Every album in the collection
+    #[arg(long)]
+    all: bool,
+    /// Every album by this artist
+    #[arg(long)]
+    artist: Option<String>,
+    /// A single album, by its id
+    #[arg(long)]
+    album: Option<u32>,
+}
+
+impl From<SelectionArgs> for Selection {
+    fn from(value: SelectionArgs) -> Self {
+        match (value.artist, value.album) {
+            (Some(artist), _) => Selection::Artist(artist),
+            (_, Some(album)) => Selection::Album(album),
+            _ => Selection::All,
+        }
+    }
+}
+
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
     let collection_path = PathBuf::from_str("collection.jsonl")?;
+    let queue_path = PathBuf::from_str("download_queue.jsonl")?;
+    let usage_path = PathBuf::from_str("album_usage.jsonl")?;
+    let config = Config::load(&PathBuf::from_str("config.json")?)?;
+
+    let layout = LibraryLayout::from_config(&config)?;
+    let mut bandcamp = BandcampSource::load(collection_path, layout.clone())?;
+    // Download links in the cached collection expire, so sync always gets a fresh one
+    if matches!(args.command, Some(Command::Sync { .. })) || !bandcamp.is_cached() {
+        bandcamp.sync()?;
+    }
 
-    let collection = match File::open(&collection_path) {
-        Ok(file) => read_lines_from_file(file)?,
-        Err(_) => login_and_cache_collection(&collection_path, 5)?,
-    };
+    if let Some(Command::Relocate {
+        from_root,
+        from_template,
+        dry_run,
+    }) = &args.command
+    {
+        let old_layout = LibraryLayout::new(
+            from_root.as_ref().unwrap_or(&config.library_root),
+            from_template.as_ref().unwrap_or(&config.path_template),
+        )?;
+        return relocate(&bandcamp, old_layout, &layout, *dry_run);
+    }
+    if let Some(Command::Import { paths, dry_run }) = &args.command {
+        let collection = Collection::from_sources(&[&bandcamp], layout.root);
+        return import_albums(paths, &collection, *dry_run);
+    }
+
+    let mut local = LocalSource::new(config.local_music_dirs.clone());
+    local.sync()?;
+    let skipped_dirs = local
+        .skipped_directories()
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    let collection = Collection::from_sources(&[&bandcamp, &local], layout.root);
+
+    if !skipped_dirs.is_empty() && args.command.is_some() {
+        eprintln!("Skipped local music folders that aren't there or can't be read: {skipped_dirs}");
+    }
+    match args.command {
+        Some(Command::Doctor { remove_damaged }) => return doctor(collection, remove_damaged),
+        Some(Command::Download { selection, json }) => {
+            return headless::download(
+                collection,
+                selection.into(),
+                download_runtime(),
+                &config,
+                queue_path,
+                json,
+            );
+        }
+        Some(Command::Sync { json }) => {
+            return headless::download(
+                collection,
+                Selection::All,
+                download_runtime(),
+                &config,
+                queue_path,
+                json,
+            );
+        }
+        Some(Command::Stats { json }) => return stats(&collection, json),
+        Some(Command::Relocate { .. } | Command::Import { .. }) | None => (),
+    }
 
     // Puts the terminal in raw mode, which disables line buffering (so rip to ctrl+c response)
     let mut terminal = ratatui::init();
 
     // Sound gets killed when this is dropped, so it has to live as long as the whole app.
     let stream_handle = OutputStreamBuilder::open_default_stream()?;
-    // In testing, 2 thread wasn't any faster than 1 threads
-    // That could change with sufficient concurrent downloads,
-    // but I kind of like the idea of limiting the number of threads.
-    let download_runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(1)
-        .enable_all()
-        .build()
-        .unwrap();
-    let mut app = App::new(collection, &stream_handle, download_runtime);
+    let mut app = App::new(
+        collection,
+        &stream_handle,
+        download_runtime(),
+        config,
+        queue_path,
+        usage_path,
+    );
+    if args.verify {
+        app.verify_library();
+    }
 
     let ui_thread_mpsc_tx = app.clone_sender();
+    if !skipped_dirs.is_empty() {
+        ui_thread_mpsc_tx.send(rusty_piano::events::Event::Error(anyhow!(
+            "Skipped local music folders that aren't there or can't be read: {skipped_dirs}"
+        )))?;
+    }
 
     // TODO: error handling. If this input thread panics, how do I notify the main thread and exit the application?
     thread::spawn(move || {
@@ -50,7 +217,7 @@ fn main() -> Result<()> {
     while !app.exit {
         terminal.draw(|frame| app.render(frame.area(), frame.buffer_mut()))?;
 
-        app.handle_next_event()?;
+        app.handle_events()?;
 
         // 16 milliseconds should yield about 60 FPS (assuming events are handled fast enough, of course)
         thread::sleep(Duration::from_millis(16));
@@ -59,33 +226,166 @@ fn main() -> Result<()> {
     // Returns the terminal back to normal mode
     ratatui::restore();
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn login_and_cache_collection(collection_path: &Path, page_size: usize) -> Result<Vec<Item>> {
-    let mut client = None;
-    while client.is_none() {
-        println!("Bandcamp username:");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input)?;
-        let username = input.trim_end().to_owned();
+// Only Bandcamp albums are moved, local music folders aren't part of the library
+fn relocate(
+    bandcamp: &BandcampSource,
+    old_layout: LibraryLayout,
+    new_layout: &LibraryLayout,
+    dry_run: bool,
+) -> Result<ExitCode> {
+    let old_root = old_layout.root.clone();
+    let old = Collection::from_sources(&[&bandcamp.with_layout(old_layout)], old_root.clone());
+    let new = Collection::from_sources(&[bandcamp], new_layout.root.clone());
+    let relocation = relocate::plan(&old, &new);
 
-        println!("Password:");
-        let password = rpassword::read_password()?;
+    for m in &relocation.moves {
+        println!("{} -> {}", m.from.display(), m.to.display());
+    }
+    for conflict in &relocation.conflicts {
+        println!(
+            "⚠ {} stays, {} is already there",
+            conflict.from.display(),
+            conflict.to.display()
+        );
+    }
 
-        match BandCampClient::new(&username, &password) {
-            Ok(c) => client = Some(c),
-            Err(err) => println!("{err:?}"),
+    if !dry_run {
+        relocation.apply(&old_root)?;
+    }
+    println!(
+        "{} {} files, {} left where they are",
+        match dry_run {
+            true => "Would move",
+            false => "Moved",
+        },
+        relocation.moves.len(),
+        relocation.conflicts.len()
+    );
+
+    Ok(match relocation.conflicts.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+// Only into Bandcamp albums, local music folders aren't part of the library
+fn import_albums(paths: &[PathBuf], collection: &Collection, dry_run: bool) -> Result<ExitCode> {
+    let plan = import::plan(paths, collection)?;
+
+    for album in &plan.albums {
+        println!("💿 {} ({})", album.album_title, album.from.display());
+        for (_, to) in &album.files {
+            println!("  -> {}", to.display());
         }
+        for path in &album.already_there {
+            println!("  ✔ {} is already there", path.display());
+        }
+        for name in &album.unmatched {
+            println!("  ⚠ {name} isn't one of the album's tracks");
+        }
+    }
+    for path in &plan.unknown {
+        println!(
+            "⚠ {} doesn't match an album in the collection",
+            path.display()
+        );
+    }
+
+    if !dry_run {
+        plan.apply()?;
+    }
+    println!(
+        "{} {} files into {} albums, {} not matched",
+        match dry_run {
+            true => "Would import",
+            false => "Imported",
+        },
+        plan.albums
+            .iter()
+            .map(|album| album.files.len())
+            .sum::<usize>(),
+        plan.albums.len(),
+        plan.unknown.len()
+    );
+
+    Ok(match plan.unknown.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+fn download_runtime() -> Runtime {
+    // In testing, 2 thread wasn't any faster than 1 threads
+    // That could change with sufficient concurrent downloads,
+    // but I kind of like the idea of limiting the number of threads.
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn doctor(mut collection: Collection, remove_damaged: bool) -> Result<ExitCode> {
+    let report = collection.verify()?;
+
+    for damaged in &report.damaged {
+        println!(
+            "💔 {} - {}: {} ({})",
+            damaged.album,
+            damaged.track,
+            damaged.damage,
+            damaged.file_path.display()
+        );
     }
+    println!(
+        "Checked {} tracks, {} damaged",
+        report.checked,
+        report.damaged.len()
+    );
 
-    print!("Caching collection... ");
-    std::io::stdout().flush()?;
-    let items = client.unwrap().get_entire_collection(page_size)?;
-    println!("Done!");
+    if remove_damaged {
+        collection.remove_damaged()?;
+    }
 
-    let file = File::create(&collection_path)?;
-    write_lines_to_file(file, items.iter())?;
+    Ok(match report.damaged.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+fn stats(collection: &Collection, json: bool) -> Result<ExitCode> {
+    let stats = LibraryStats::of(collection.albums(), collection.library_root());
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    Ok(items)
+    for (name, counts) in [("Albums", &stats.albums), ("Tracks", &stats.tracks)] {
+        println!(
+            "{name}: {} ({} downloaded, {} failed, {} missing)",
+            counts.total, counts.downloaded, counts.failed, counts.missing
+        );
+    }
+    println!("Listening time on disk: {:.1} hours", stats.listening_hours);
+    println!("Library size: {}", format_bytes(stats.library_bytes as f64));
+    println!("Largest albums:");
+    for album in &stats.largest_albums {
+        println!(
+            "  {} {} by {}",
+            format_bytes(album.bytes as f64),
+            album.title,
+            album.artist
+        );
+    }
+    println!("By artist:");
+    for artist in &stats.artists {
+        println!("  {} {}", format_bytes(artist.bytes as f64), artist.artist);
+        for album in &artist.albums {
+            println!("    {} {}", format_bytes(album.bytes as f64), album.title);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
 }
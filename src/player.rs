@@ -2,7 +2,10 @@ use anyhow::Result;
 use ratatui::prelude::*;
 use ratatui::widgets::{Block, List, ListState, StatefulWidget, Widget};
 use rodio::{Decoder, OutputStream, Sink};
-use std::{fs::File, path::PathBuf, usize};
+use std::{fs::File, path::Path};
+
+use crate::collection::{Album, TrackStatus};
+use crate::download_manager::is_same_track;
 
 pub struct Player {
     sink: Sink,
@@ -25,7 +28,7 @@ impl Player {
         self.sink.stop();
         self.tracks_state = ListState::default();
         self.album = Some(album);
-        self.play_track(0)
+        self.play_next_track()
     }
 
     // TODO: The album isn't being unloaded when the last song finishes,
@@ -39,6 +42,13 @@ impl Player {
         }
     }
 
+    // Whether the file belongs to the album that's loaded
+    pub fn is_loaded(&self, file_path: &Path) -> bool {
+        self.album
+            .as_ref()
+            .is_some_and(|album| album.tracks.iter().any(|t| t.file_path == file_path))
+    }
+
     pub fn toggle_playback(&self) {
         match self.sink.is_paused() {
             true => self.sink.play(),
@@ -46,26 +56,19 @@ impl Player {
         }
     }
 
+    // Tracks that aren't on disk are skipped. Without an earlier track, the current one starts over.
     pub fn play_previous_track(&mut self) -> Result<()> {
-        if self.album.is_some() {
-            let current_index = self.tracks_state.selected();
-            let previous_index = current_index.filter(|i| *i > 0).map_or(0, |i| i - 1);
-
-            self.play_track(previous_index)
-        } else {
-            Ok(())
-        }
+        let current_index = self.tracks_state.selected().unwrap_or(0);
+        self.play_first_playable((0..current_index).rev().chain([current_index]));
+        Ok(())
     }
 
+    // Tracks that aren't on disk are skipped
     pub fn play_next_track(&mut self) -> Result<()> {
-        if self.album.is_some() {
-            let current_index = self.tracks_state.selected();
-            let track_index = current_index.map_or(0, |i| i + 1);
-
-            self.play_track(track_index)
-        } else {
-            Ok(())
-        }
+        let start_index = self.tracks_state.selected().map_or(0, |i| i + 1);
+        let track_count = self.album.as_ref().map_or(0, |album| album.tracks.len());
+        self.play_first_playable(start_index..track_count);
+        Ok(())
     }
 
     // Plays the next track iff a track is not currently loaded
@@ -77,31 +80,68 @@ impl Player {
         }
     }
 
-    fn play_track(&mut self, track_index: usize) -> Result<()> {
-        if let Some(track) = self.album.as_ref().unwrap().tracks.get(track_index) {
-            self.sink.stop();
-            let file = File::open(&track.file_path)?;
-            let source = Decoder::try_from(file)?;
-            self.sink.append(source);
-            self.sink.play();
-
-            self.tracks_state.select(Some(track_index));
+    // Called when a track of the loaded album finishes downloading (or is put in the library,
+    // maybe in another format)
+    pub fn set_playable(&mut self, file_path: &Path) {
+        if let Some(track) = self.album.as_mut().and_then(|album| {
+            album
+                .tracks
+                .iter_mut()
+                .find(|t| is_same_track(&t.file_path, file_path))
+        }) {
+            track.file_path = file_path.to_owned();
+            track.status = TrackStatus::Complete;
         }
+    }
 
-        Ok(())
+    // Called when a track of the loaded album is removed from the library
+    pub fn set_missing(&mut self, file_path: &Path) {
+        if let Some(track) = self.album.as_mut().and_then(|album| {
+            album
+                .tracks
+                .iter_mut()
+                .find(|t| is_same_track(&t.file_path, file_path))
+        }) {
+            track.status = TrackStatus::Missing;
+        }
     }
-}
 
-pub struct Album {
-    pub title: String,
-    pub band_name: String,
-    pub tracks: Vec<Track>,
-}
+    // Plays the first of the tracks (by index) that's on disk and opens. Tracks that don't open
+    // (deleted, damaged, ...) are marked that way and skipped.
+    fn play_first_playable(&mut self, indexes: impl Iterator<Item = usize>) {
+        let Some(album) = self.album.as_mut() else {
+            return;
+        };
 
-pub struct Track {
-    pub number: u8,
-    pub title: String,
-    pub file_path: PathBuf,
+        for track_index in indexes {
+            let Some(track) = album
+                .tracks
+                .get_mut(track_index)
+                .filter(|track| track.status == TrackStatus::Complete)
+            else {
+                continue;
+            };
+
+            let source = File::open(&track.file_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| Ok(Decoder::try_from(file)?));
+            match source {
+                Ok(source) => {
+                    self.sink.stop();
+                    self.sink.append(source);
+                    self.sink.play();
+                    self.tracks_state.select(Some(track_index));
+                    return;
+                }
+                Err(_) => {
+                    track.status = match track.file_path.exists() {
+                        true => TrackStatus::Corrupt,
+                        false => TrackStatus::Missing,
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl Widget for &mut Player {
@@ -113,14 +153,17 @@ impl Widget for &mut Player {
             album
                 .tracks
                 .iter()
-                .map(|track| format!("{:02} - {}", track.number, track.title))
+                .map(|track| match track.status {
+                    TrackStatus::Complete => format!("{:02} - {}", track.number, track.title),
+                    _ => format!("{:02} - {} 💾", track.number, track.title),
+                })
                 .collect()
         });
 
         let title = self
             .album
             .as_ref()
-            .map_or(format!(""), |a| format!("{} by {}", a.title, a.band_name));
+            .map_or(String::new(), |album| album.title.clone());
 
         let list = List::new(tracks)
             .highlight_symbol("▶️")
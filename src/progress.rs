@@ -0,0 +1,155 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::collection::TrackStatus;
+
+// How far along an album download is, worked out from the state of its tracks
+#[derive(Clone, Default, Debug)]
+pub struct DownloadProgress {
+    pub tracks_done: usize,
+    pub tracks_total: usize,
+    // Finished tracks count as a whole, tracks in flight count as the fraction received
+    tracks_fraction: f64,
+    // Bytes still expected from the tracks that are downloading
+    remaining_known_bytes: u64,
+    // Expected sizes of the tracks that are downloading
+    known_sizes: Vec<u64>,
+    tracks_not_started: usize,
+}
+
+impl DownloadProgress {
+    // Tracks that are missing (and not queued) aren't part of the download
+    pub fn of<'a>(statuses: impl Iterator<Item = &'a TrackStatus>) -> Self {
+        let mut progress = Self::default();
+        for status in statuses {
+            match status {
+                TrackStatus::Missing | TrackStatus::Corrupt => continue,
+                TrackStatus::Queued => progress.tracks_not_started += 1,
+                TrackStatus::Downloading { received, total } => {
+                    if let Some(total) = total.filter(|total| *total > 0) {
+                        progress.tracks_fraction += *received as f64 / total as f64;
+                        progress.remaining_known_bytes += total.saturating_sub(*received);
+                        progress.known_sizes.push(total);
+                    }
+                }
+                TrackStatus::Complete => {
+                    progress.tracks_done += 1;
+                    progress.tracks_fraction += 1.0;
+                }
+                TrackStatus::Failed(_) => (),
+            }
+            progress.tracks_total += 1;
+        }
+        progress
+    }
+
+    pub fn ratio(&self) -> f64 {
+        match self.tracks_total {
+            0 => 1.0,
+            total => (self.tracks_fraction / total as f64).min(1.0),
+        }
+    }
+
+    // Guesses the bytes still to come, assuming tracks that haven't started are average sized
+    pub fn remaining_bytes(&self) -> u64 {
+        let average = match self.known_sizes.len() {
+            0 => 0,
+            n => self.known_sizes.iter().sum::<u64>() / n as u64,
+        };
+
+        self.remaining_known_bytes + self.tracks_not_started as u64 * average
+    }
+
+    // e.g. "[██████░░░░] 3/8"
+    pub fn gauge(&self, width: usize) -> String {
+        let filled = (self.ratio() * width as f64) as usize;
+        format!(
+            "[{}{}] {}/{}",
+            "█".repeat(filled),
+            "░".repeat(width - filled),
+            self.tracks_done,
+            self.tracks_total
+        )
+    }
+}
+
+// Keeps a sliding window of received bytes to work out the overall download speed
+pub struct DownloadMeter {
+    samples: VecDeque<(Instant, u64)>,
+    window: Duration,
+}
+
+impl Default for DownloadMeter {
+    fn default() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            window: Duration::from_secs(5),
+        }
+    }
+}
+
+impl DownloadMeter {
+    pub fn record(&mut self, bytes: u64) {
+        if bytes > 0 {
+            self.samples.push_back((Instant::now(), bytes));
+        }
+    }
+
+    pub fn bytes_per_second(&mut self) -> f64 {
+        let now = Instant::now();
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > self.window)
+        {
+            self.samples.pop_front();
+        }
+
+        let bytes = self.samples.iter().map(|(_, b)| b).sum::<u64>();
+        bytes as f64 / self.window.as_secs_f64()
+    }
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_counts_finished_and_partial_tracks() {
+        let statuses = [
+            TrackStatus::Complete,
+            TrackStatus::Downloading {
+                received: 50,
+                total: Some(100),
+            },
+            TrackStatus::Queued,
+            TrackStatus::Queued,
+            TrackStatus::Missing,
+        ];
+        let progress = DownloadProgress::of(statuses.iter());
+
+        assert_eq!(progress.ratio(), 1.5 / 4.0);
+        assert_eq!(progress.remaining_bytes(), 50 + 2 * 100);
+        assert_eq!(progress.gauge(4), "[█░░░] 1/4");
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::collection::Collection;
+use crate::download_manager::part_path;
+use crate::storage::{move_file, remove_empty_dirs};
+use crate::verify::{MANIFEST_FILE_NAME, Manifest};
+
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+// What it takes to go from one library layout to another
+#[derive(Default)]
+pub struct Relocation {
+    pub moves: Vec<Move>,
+    // Files that stay put, because something is already where they'd go (or another file of the
+    // old layout is going there)
+    pub conflicts: Vec<Move>,
+    // Album directories that change, so the checksums can follow the files
+    directories: Vec<(PathBuf, PathBuf)>,
+}
+
+// Works out where the tracks (finished or partly downloaded) and extras of the old layout go in
+// the new one. Both collections have to be made from the same Bandcamp items.
+pub fn plan(old: &Collection, new: &Collection) -> Relocation {
+    let mut relocation = Relocation::default();
+    let mut destinations = HashSet::new();
+    for (old_album, new_album) in old.albums().zip(new.albums()) {
+        let mut files = Vec::new();
+        for (old_track, new_track) in old_album.tracks.iter().zip(&new_album.tracks) {
+            // Tracks keep the format they're in (FLAC from a package, ...)
+            let from = &old_track.file_path;
+            let to = new_track
+                .file_path
+                .with_extension(from.extension().unwrap_or_default());
+            files.push((from.clone(), to.clone()));
+            files.push((part_path(from), part_path(&to)));
+        }
+
+        if let (Some(old_dir), Some(new_dir)) = (old_album.directory(), new_album.directory())
+            && old_dir != new_dir
+        {
+            for extra in &old_album.extras {
+                files.push((
+                    extra.clone(),
+                    new_dir.join(extra.file_name().unwrap_or_default()),
+                ));
+            }
+            relocation.directories.push((old_dir, new_dir));
+        }
+
+        for (from, to) in files {
+            if from == to || !from.exists() {
+                continue;
+            }
+            match to.exists() || !destinations.insert(to.clone()) {
+                true => relocation.conflicts.push(Move { from, to }),
+                false => relocation.moves.push(Move { from, to }),
+            }
+        }
+    }
+    relocation
+}
+
+impl Relocation {
+    // Moves the files, takes their checksums along and clears away the directories left empty
+    pub fn apply(&self, old_root: &Path) -> Result<()> {
+        for Move { from, to } in &self.moves {
+            move_file(from, to)?;
+        }
+
+        for (old_dir, new_dir) in &self.directories {
+            if old_dir.join(MANIFEST_FILE_NAME).exists() {
+                let renames = self
+                    .moves
+                    .iter()
+                    .filter(|m| m.from.parent() == Some(old_dir.as_path()))
+                    .filter_map(|m| {
+                        let from = m.from.file_name()?.to_string_lossy().into_owned();
+                        let to = m.to.file_name()?.to_string_lossy().into_owned();
+                        Some((from, to))
+                    })
+                    .collect::<HashMap<String, String>>();
+
+                let mut manifest = Manifest::load(new_dir)?;
+                manifest.adopt(Manifest::load(old_dir)?, &renames);
+                manifest.save()?;
+                fs::remove_file(old_dir.join(MANIFEST_FILE_NAME))?;
+            }
+            remove_empty_dirs(old_dir, old_root);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::TestSource;
+
+    #[test]
+    fn tracks_going_to_the_same_place_conflict() {
+        let library_root = TestSource::library_root("relocate");
+        fs::create_dir_all(library_root.join("old")).unwrap();
+        fs::write(library_root.join("old/1.mp3"), b"1").unwrap();
+        fs::write(library_root.join("old/2.mp3"), b"2").unwrap();
+        let collection = |track_path| {
+            let source = TestSource {
+                library_root: library_root.clone(),
+                albums: vec![(1, "Album")],
+                track_path,
+            };
+            Collection::from_sources(&[&source], library_root.clone())
+        };
+
+        let old = collection(|_, track| format!("old/{}.mp3", track.number));
+        // Puts every track of the album in the same place
+        let new = collection(|_, _| "new/track.mp3".to_owned());
+        let relocation = plan(&old, &new);
+
+        assert_eq!(relocation.moves.len(), 1);
+        assert_eq!(relocation.conflicts.len(), 1);
+        assert_eq!(relocation.conflicts[0].from, library_root.join("old/2.mp3"));
+
+        fs::remove_dir_all(&library_root).unwrap();
+    }
+}
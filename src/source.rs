@@ -0,0 +1,101 @@
+use anyhow::Result;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Where albums come from: a store the tracks get downloaded from (Bandcamp), or music that's
+// on disk already (local folders). The collection, and so the app, treats them all the same.
+pub trait MusicSource {
+    // The albums as of the last sync
+    fn albums(&self) -> Vec<SourceAlbum>;
+    // Where each of the album's tracks is on disk, or goes once it's downloaded
+    fn track_locations(&self, album: &SourceAlbum) -> Vec<PathBuf>;
+    // Where to download a track of the album from, None if the source has no download for it
+    fn download_url(&self, album: &SourceAlbum, index: usize) -> Option<String>;
+    // Gets the latest album list from wherever the source keeps it (a store's API, a folder scan, ...)
+    fn sync(&mut self) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceAlbum {
+    // Has to be unique across sources
+    pub id: u32,
+    pub artist: String,
+    pub title: String,
+    pub year: Option<String>,
+    pub label: Option<String>,
+    pub artwork_url: Option<String>,
+    // Where the whole album can be downloaded in one package, see DownloadManager::download_package
+    pub package_url: Option<String>,
+    pub tracks: Vec<SourceTrack>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceTrack {
+    pub number: u8,
+    pub title: String,
+    pub duration: Option<Duration>,
+}
+
+// The first four digit number in a date, whatever format it's in
+pub fn year_of(date: &str) -> Option<&str> {
+    date.split(|c: char| !c.is_ascii_digit())
+        .find(|part| part.len() == 4)
+}
+
+// For tests: downloadable albums by "Band" with two tracks ("Song 1" and "Song 2", half an hour
+// each) that go where track_path says under library_root
+#[cfg(test)]
+pub(crate) struct TestSource {
+    pub library_root: PathBuf,
+    // Ids and titles
+    pub albums: Vec<(u32, &'static str)>,
+    pub track_path: fn(&SourceAlbum, &SourceTrack) -> String,
+}
+
+#[cfg(test)]
+impl TestSource {
+    // An empty library root in the temp directory, named after the test
+    pub fn library_root(test_name: &str) -> PathBuf {
+        let library_root = std::env::temp_dir().join(format!("rusty-piano-{test_name}-test"));
+        let _ = std::fs::remove_dir_all(&library_root);
+        std::fs::create_dir_all(&library_root).unwrap();
+        library_root
+    }
+}
+
+#[cfg(test)]
+impl MusicSource for TestSource {
+    fn albums(&self) -> Vec<SourceAlbum> {
+        let track = |number: u8| SourceTrack {
+            number,
+            title: format!("Song {number}"),
+            duration: Some(Duration::from_secs(1800)),
+        };
+        self.albums
+            .iter()
+            .map(|(id, title)| SourceAlbum {
+                id: *id,
+                artist: "Band".to_owned(),
+                title: (*title).to_owned(),
+                tracks: vec![track(1), track(2)],
+                ..SourceAlbum::default()
+            })
+            .collect()
+    }
+
+    fn track_locations(&self, album: &SourceAlbum) -> Vec<PathBuf> {
+        album
+            .tracks
+            .iter()
+            .map(|track| self.library_root.join((self.track_path)(album, track)))
+            .collect()
+    }
+
+    fn download_url(&self, _album: &SourceAlbum, _index: usize) -> Option<String> {
+        Some(String::new())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Paragraph, Widget};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::collection::{Album, DownloadStatus, TrackStatus};
+use crate::local::read_duration;
+use crate::progress::{format_bytes, format_duration};
+use crate::storage::directory_size;
+
+// How many of the largest albums are listed
+const LARGEST_ALBUMS: usize = 10;
+
+// A snapshot of the collection and what it takes up on disk. Serialized as is for `stats --json`.
+#[derive(Debug, Default, Serialize)]
+pub struct LibraryStats {
+    pub albums: StatusCounts,
+    pub tracks: StatusCounts,
+    // Of the tracks on disk. When the source has no duration for a track (collections cached
+    // before durations were kept), it's read from the file.
+    pub listening_hours: f64,
+    // Everything under the library root, extras and leftovers included
+    pub library_bytes: u64,
+    // Biggest first
+    pub artists: Vec<ArtistUsage>,
+    pub largest_albums: Vec<AlbumUsage>,
+}
+
+// Albums and tracks that are downloading (or partly downloaded) only count towards the total
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct StatusCounts {
+    pub total: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+    pub missing: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArtistUsage {
+    pub artist: String,
+    pub bytes: u64,
+    // Biggest first
+    pub albums: Vec<AlbumUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlbumUsage {
+    pub album_id: u32,
+    pub artist: String,
+    pub title: String,
+    pub bytes: u64,
+}
+
+impl LibraryStats {
+    // Disk usage is only for albums in the library, local music folders are someone else's business.
+    // Takes a while on a big library (it reads the size of every file, and the duration of tracks
+    // the source has none for), so the TUI runs it in the background.
+    pub fn of<'a>(albums: impl IntoIterator<Item = &'a Album>, library_root: &Path) -> Self {
+        let mut stats = LibraryStats {
+            library_bytes: directory_size(library_root),
+            ..LibraryStats::default()
+        };
+
+        let mut listening_time = Duration::ZERO;
+        let mut artists = BTreeMap::<String, Vec<AlbumUsage>>::new();
+        for album in albums {
+            stats.albums.total += 1;
+            match album.download_status() {
+                DownloadStatus::Downloaded => stats.albums.downloaded += 1,
+                DownloadStatus::DownloadFailed => stats.albums.failed += 1,
+                DownloadStatus::NotDownloaded => stats.albums.missing += 1,
+                DownloadStatus::Downloading | DownloadStatus::PartiallyDownloaded => (),
+            }
+
+            for track in &album.tracks {
+                stats.tracks.total += 1;
+                match track.status {
+                    TrackStatus::Complete => {
+                        stats.tracks.downloaded += 1;
+                        listening_time += track
+                            .duration
+                            .or_else(|| read_duration(&track.file_path))
+                            .unwrap_or_default();
+                    }
+                    TrackStatus::Failed(_) | TrackStatus::Corrupt => stats.tracks.failed += 1,
+                    TrackStatus::Missing => stats.tracks.missing += 1,
+                    TrackStatus::Queued | TrackStatus::Downloading { .. } => (),
+                }
+            }
+
+            let bytes = album.size_on_disk();
+            if album.downloadable && bytes > 0 {
+                artists
+                    .entry(album.tags.artist.clone())
+                    .or_default()
+                    .push(AlbumUsage {
+                        album_id: album.id,
+                        artist: album.tags.artist.clone(),
+                        title: album.tags.album.clone(),
+                        bytes,
+                    });
+            }
+        }
+        stats.listening_hours = listening_time.as_secs_f64() / 3600.0;
+
+        stats.artists = artists
+            .into_iter()
+            .map(|(artist, mut albums)| {
+                albums.sort_by_key(|usage| Reverse(usage.bytes));
+                ArtistUsage {
+                    artist,
+                    bytes: albums.iter().map(|album| album.bytes).sum(),
+                    albums,
+                }
+            })
+            .collect();
+        stats.artists.sort_by_key(|usage| Reverse(usage.bytes));
+
+        stats.largest_albums = stats
+            .artists
+            .iter()
+            .flat_map(|artist| artist.albums.iter().cloned())
+            .collect();
+        stats
+            .largest_albums
+            .sort_by_key(|usage| Reverse(usage.bytes));
+        stats.largest_albums.truncate(LARGEST_ALBUMS);
+
+        stats
+    }
+
+    // While the statistics are worked out
+    pub fn render_pending(area: Rect, buf: &mut Buffer) {
+        Paragraph::new("Working out the statistics...")
+            .block(
+                Block::bordered()
+                    .title("Statistics")
+                    .title_bottom("'s/esc' close"),
+            )
+            .render(area, buf);
+    }
+
+    pub fn render(&self, area: Rect, buf: &mut Buffer) {
+        let counts = |name: &str, counts: &StatusCounts| {
+            Line::from(format!(
+                "{name}: {} | ✅ {} downloaded | 🚨 {} failed | 💾 {} missing",
+                counts.total, counts.downloaded, counts.failed, counts.missing
+            ))
+        };
+        let size = |bytes: u64| format_bytes(bytes as f64);
+
+        let mut text = vec![
+            counts("Albums", &self.albums),
+            counts("Tracks", &self.tracks),
+            Line::from(format!(
+                "Listening time on disk: {}",
+                format_duration(Duration::from_secs_f64(self.listening_hours * 3600.0))
+            )),
+            Line::from(format!("Library size: {}", size(self.library_bytes))),
+            Line::from(""),
+            Line::from("Largest albums").bold(),
+        ];
+        text.extend(self.largest_albums.iter().map(|album| {
+            Line::from(format!(
+                "{} - {} by {}",
+                size(album.bytes),
+                album.title,
+                album.artist
+            ))
+        }));
+        text.push(Line::from(""));
+        text.push(Line::from("By artist").bold());
+        text.extend(self.artists.iter().map(|artist| {
+            Line::from(format!(
+                "{} - {} ({} albums)",
+                size(artist.bytes),
+                artist.artist,
+                artist.albums.len()
+            ))
+        }));
+
+        Paragraph::new(text)
+            .block(
+                Block::bordered()
+                    .title("Statistics")
+                    .title_bottom("'s/esc' close"),
+            )
+            .render(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::Collection;
+    use crate::source::TestSource;
+    use std::fs;
+
+    #[test]
+    fn stats_count_what_is_on_disk() {
+        let library_root = TestSource::library_root("stats");
+        for (file, size) in [("Small/1.mp3", 10), ("Small/2.mp3", 10), ("Big/1.mp3", 100)] {
+            let path = library_root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, vec![0; size]).unwrap();
+        }
+        let source = TestSource {
+            library_root: library_root.clone(),
+            albums: vec![(1, "Small"), (2, "Big"), (3, "Missing")],
+            track_path: |album, track| format!("{}/{}.mp3", album.title, track.number),
+        };
+        let collection = Collection::from_sources(&[&source], library_root.clone());
+
+        let stats = LibraryStats::of(collection.albums(), &library_root);
+
+        let counts = |total, downloaded, missing| StatusCounts {
+            total,
+            downloaded,
+            failed: 0,
+            missing,
+        };
+        assert_eq!(stats.albums, counts(3, 1, 1));
+        assert_eq!(stats.tracks, counts(6, 3, 3));
+        assert_eq!(stats.listening_hours, 1.5);
+        assert_eq!(stats.library_bytes, 120);
+        assert_eq!(stats.artists.len(), 1);
+        assert_eq!(stats.artists[0].bytes, 120);
+        let largest = stats
+            .largest_albums
+            .iter()
+            .map(|a| a.title.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(largest, ["Big", "Small"]);
+
+        fs::remove_dir_all(&library_root).unwrap();
+    }
+}
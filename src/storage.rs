@@ -0,0 +1,131 @@
+use anyhow::Result;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::download_manager::part_path;
+use crate::progress::format_bytes;
+
+// Why a download was turned down before it started
+#[derive(Debug, Error, PartialEq)]
+pub enum SpaceError {
+    #[error("Not enough disk space: {} needed, {} free", format_bytes(*.needed as f64), format_bytes(*.available as f64))]
+    DiskFull { needed: u64, available: u64 },
+    #[error("Library would outgrow its limit of {}: {} used, {} needed", format_bytes(*.max as f64), format_bytes(*.used as f64), format_bytes(*.needed as f64))]
+    LibraryFull { needed: u64, used: u64, max: u64 },
+}
+
+pub fn check_space(
+    needed: u64,
+    available: u64,
+    library_size: u64,
+    max_library_size: Option<u64>,
+) -> Result<(), SpaceError> {
+    if needed > available {
+        return Err(SpaceError::DiskFull { needed, available });
+    }
+
+    match max_library_size {
+        Some(max) if library_size + needed > max => Err(SpaceError::LibraryFull {
+            needed,
+            used: library_size,
+            max,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Free space on the volume the path is (or will be) on
+pub fn free_space(path: &Path) -> Result<u64> {
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    Ok(fs4::available_space(existing)?)
+}
+
+// Total size of the files under path, nothing if it doesn't exist (yet)
+pub fn directory_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => directory_size(&entry.path()),
+            Ok(_) => entry.metadata().map_or(0, |metadata| metadata.len()),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+// Removes dir and its parents, as long as they're empty and inside root
+pub fn remove_empty_dirs(dir: &Path, root: &Path) {
+    for ancestor in dir.ancestors() {
+        if ancestor == root || !ancestor.starts_with(root) || fs::remove_dir(ancestor).is_err() {
+            break;
+        }
+    }
+}
+
+// Renames where it can, and copies (then deletes) when the new place is on another file system
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if fs::rename(from, to).is_err() {
+        if let Err(err) = fs::copy(from, to) {
+            let _ = fs::remove_file(to);
+            return Err(err.into());
+        }
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+// Writes to a .part file next to path, which is only renamed to path once it's all there, so
+// an interruption never leaves half a file that looks complete
+pub fn write_through_part_file(path: &Path, mut contents: impl Read) -> Result<()> {
+    let part_path = part_path(path);
+    let mut file = File::create(&part_path)?;
+    io::copy(&mut contents, &mut file)?;
+    file.sync_all()?;
+    fs::rename(part_path, path)?;
+    Ok(())
+}
+
+// The file's extension in lowercase, empty if it has none
+pub fn lowercase_extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_have_to_fit_on_disk_and_in_the_library() {
+        assert_eq!(check_space(10, 100, 50, Some(100)), Ok(()));
+        assert_eq!(
+            check_space(200, 100, 50, None),
+            Err(SpaceError::DiskFull {
+                needed: 200,
+                available: 100
+            })
+        );
+        assert_eq!(
+            check_space(60, 100, 50, Some(100)),
+            Err(SpaceError::LibraryFull {
+                needed: 60,
+                used: 50,
+                max: 100
+            })
+        );
+    }
+}
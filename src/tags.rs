@@ -0,0 +1,439 @@
+use anyhow::Result;
+use id3::TagLike;
+use id3::frame::{Picture, PictureType};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::storage::lowercase_extension;
+
+// What happens to the tags of a track once it's downloaded
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TagMode {
+    // Bandcamp's details replace whatever the file had
+    Overwrite,
+    // Only what the file doesn't have yet gets added
+    FillMissing,
+    // The file is left as it came
+    Skip,
+}
+
+// What Bandcamp knows about an album, for tagging its tracks
+#[derive(Debug, Clone, Default)]
+pub struct AlbumTags {
+    pub artist: String,
+    pub album: String,
+    pub year: Option<String>,
+    pub label: Option<String>,
+    // Where to get the cover art when the album doesn't come with a cover file
+    pub artwork_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackTags {
+    pub album: AlbumTags,
+    pub title: String,
+    pub number: u8,
+    pub total: u8,
+}
+
+pub struct Artwork {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Artwork {
+    // The cover that came with the album package (cover.jpg, folder.png, ...)
+    pub fn find(album_dir: &Path) -> Result<Option<Self>> {
+        let Ok(entries) = fs::read_dir(album_dir) else {
+            return Ok(None);
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            let mime_type = match lowercase_extension(&path).as_str() {
+                "jpg" | "jpeg" => "image/jpeg",
+                "png" => "image/png",
+                _ => continue,
+            };
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            if matches!(stem.to_lowercase().as_str(), "cover" | "folder") {
+                return Ok(Some(Self {
+                    mime_type: mime_type.to_owned(),
+                    data: fs::read(path)?,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Field {
+    Artist,
+    Album,
+    Title,
+    Track,
+    TrackTotal,
+    Year,
+    Label,
+}
+
+impl TrackTags {
+    fn values(&self) -> Vec<(Field, String)> {
+        let mut values = vec![
+            (Field::Artist, self.album.artist.clone()),
+            (Field::Album, self.album.album.clone()),
+            (Field::Title, self.title.clone()),
+            (Field::Track, self.number.to_string()),
+            (Field::TrackTotal, self.total.to_string()),
+        ];
+        if let Some(year) = &self.album.year {
+            values.push((Field::Year, year.clone()));
+        }
+        if let Some(label) = &self.album.label {
+            values.push((Field::Label, label.clone()));
+        }
+        values
+    }
+}
+
+// Tags an MP3 (ID3v2.4) or FLAC (Vorbis comments) file with the track's details and artwork.
+// Other formats are left as they are.
+pub fn write_tags(
+    path: &Path,
+    tags: &TrackTags,
+    artwork: Option<&Artwork>,
+    mode: TagMode,
+) -> Result<()> {
+    let extension = lowercase_extension(path);
+    let overwrite = mode == TagMode::Overwrite;
+
+    match (mode, extension.as_str()) {
+        (TagMode::Skip, _) => Ok(()),
+        (_, "mp3") => write_id3(path, tags, artwork, overwrite),
+        (_, "flac") => flac::write(path, tags, artwork, overwrite),
+        _ => Ok(()),
+    }
+}
+
+fn write_id3(
+    path: &Path,
+    tags: &TrackTags,
+    artwork: Option<&Artwork>,
+    overwrite: bool,
+) -> Result<()> {
+    let mut tag = id3::no_tag_ok(id3::Tag::read_from_path(path))?.unwrap_or_default();
+
+    for (field, value) in tags.values() {
+        let present = match field {
+            Field::Artist => tag.artist().is_some(),
+            Field::Album => tag.album().is_some(),
+            Field::Title => tag.title().is_some(),
+            Field::Track => tag.track().is_some(),
+            Field::TrackTotal => tag.total_tracks().is_some(),
+            Field::Year => tag.date_recorded().is_some() || tag.year().is_some(),
+            Field::Label => tag.get("TPUB").is_some(),
+        };
+        if present && !overwrite {
+            continue;
+        }
+
+        match field {
+            Field::Artist => tag.set_artist(value),
+            Field::Album => tag.set_album(value),
+            Field::Title => tag.set_title(value),
+            Field::Track => tag.set_track(tags.number.into()),
+            Field::TrackTotal => tag.set_total_tracks(tags.total.into()),
+            Field::Year => tag.set_date_recorded(value.parse()?),
+            Field::Label => tag.set_text("TPUB", value),
+        }
+    }
+
+    if let Some(artwork) = artwork {
+        let has_cover = tag
+            .pictures()
+            .any(|picture| picture.picture_type == PictureType::CoverFront);
+        if overwrite || !has_cover {
+            tag.remove_picture_by_type(PictureType::CoverFront);
+            tag.add_frame(Picture {
+                mime_type: artwork.mime_type.clone(),
+                picture_type: PictureType::CoverFront,
+                description: String::new(),
+                data: artwork.data.clone(),
+            });
+        }
+    }
+
+    tag.write_to_path(path, id3::Version::Id3v24)?;
+    Ok(())
+}
+
+// FLAC keeps its tags in metadata blocks between the "fLaC" marker and the audio:
+// https://xiph.org/flac/format.html#metadata_block
+mod flac {
+    use anyhow::{Result, anyhow};
+    use std::fs::{self, File};
+    use std::io::{self, BufReader, BufWriter, Read, Write};
+    use std::path::Path;
+
+    use super::{Artwork, Field, TrackTags};
+    use crate::download_manager::part_path;
+
+    const MARKER: &[u8; 4] = b"fLaC";
+    const PADDING: u8 = 1;
+    const VORBIS_COMMENT: u8 = 4;
+    const PICTURE: u8 = 6;
+    const FRONT_COVER: u32 = 3;
+    // Block lengths are 24 bit
+    const MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+
+    struct Block {
+        kind: u8,
+        data: Vec<u8>,
+    }
+
+    impl Block {
+        fn is_front_cover(&self) -> bool {
+            self.kind == PICTURE && self.data.starts_with(&FRONT_COVER.to_be_bytes())
+        }
+    }
+
+    // The blocks are rewritten into a copy of the file, which replaces it once it's complete
+    pub fn write(
+        path: &Path,
+        tags: &TrackTags,
+        artwork: Option<&Artwork>,
+        overwrite: bool,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut blocks = read_blocks(&mut reader)?;
+
+        let mut comments = match blocks.iter().position(|b| b.kind == VORBIS_COMMENT) {
+            Some(index) => Comments::parse(&blocks.remove(index).data)?,
+            None => Comments::default(),
+        };
+        for (field, value) in tags.values() {
+            let key = vorbis_key(field);
+            if overwrite || !comments.has(key) {
+                comments.set(key, value);
+            }
+        }
+        // STREAMINFO has to stay the first block
+        blocks.insert(
+            1.min(blocks.len()),
+            Block {
+                kind: VORBIS_COMMENT,
+                data: comments.to_bytes(),
+            },
+        );
+
+        if let Some(artwork) = artwork
+            && (overwrite || !blocks.iter().any(Block::is_front_cover))
+        {
+            blocks.retain(|block| !block.is_front_cover());
+            blocks.push(Block {
+                kind: PICTURE,
+                data: picture(artwork),
+            });
+        }
+        // Padding is there for tags to grow into, so it goes after them
+        blocks.sort_by_key(|block| block.kind == PADDING);
+
+        let tagged_path = part_path(path);
+        let mut writer = BufWriter::new(File::create(&tagged_path)?);
+        writer.write_all(MARKER)?;
+        for (index, block) in blocks.iter().enumerate() {
+            if block.data.len() > MAX_BLOCK_LEN {
+                return Err(anyhow!("FLAC metadata block too big to write"));
+            }
+            let last = if index + 1 == blocks.len() { 0x80 } else { 0 };
+            let len = (block.data.len() as u32).to_be_bytes();
+            writer.write_all(&[block.kind | last, len[1], len[2], len[3]])?;
+            writer.write_all(&block.data)?;
+        }
+        io::copy(&mut reader, &mut writer)?;
+        writer.into_inner()?.sync_all()?;
+
+        fs::rename(tagged_path, path)?;
+        Ok(())
+    }
+
+    fn read_blocks(reader: &mut impl Read) -> Result<Vec<Block>> {
+        let mut marker = [0; 4];
+        reader.read_exact(&mut marker)?;
+        if &marker != MARKER {
+            return Err(anyhow!("Not a FLAC file"));
+        }
+
+        let mut blocks = Vec::new();
+        loop {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+            let mut data = vec![0; len as usize];
+            reader.read_exact(&mut data)?;
+            blocks.push(Block {
+                kind: header[0] & 0x7f,
+                data,
+            });
+
+            if header[0] & 0x80 != 0 {
+                return Ok(blocks);
+            }
+        }
+    }
+
+    fn vorbis_key(field: Field) -> &'static str {
+        match field {
+            Field::Artist => "ARTIST",
+            Field::Album => "ALBUM",
+            Field::Title => "TITLE",
+            Field::Track => "TRACKNUMBER",
+            Field::TrackTotal => "TRACKTOTAL",
+            Field::Year => "DATE",
+            Field::Label => "ORGANIZATION",
+        }
+    }
+
+    // Unlike the rest of FLAC, the Vorbis comment lengths are little endian
+    #[derive(Default)]
+    pub(super) struct Comments {
+        vendor: String,
+        // KEY=value, keys are case insensitive and can repeat
+        comments: Vec<String>,
+    }
+
+    impl Comments {
+        pub(super) fn parse(mut data: &[u8]) -> Result<Self> {
+            let vendor = read_string(&mut data)?;
+            let count = read_u32(&mut data)?;
+            let comments = (0..count)
+                .map(|_| read_string(&mut data))
+                .collect::<Result<Vec<String>>>()?;
+            Ok(Self { vendor, comments })
+        }
+
+        pub(super) fn has(&self, key: &str) -> bool {
+            self.values(key).next().is_some()
+        }
+
+        pub(super) fn values(&self, key: &str) -> impl Iterator<Item = &str> {
+            self.comments.iter().filter_map(move |comment| {
+                let (k, value) = comment.split_once('=')?;
+                k.eq_ignore_ascii_case(key).then_some(value)
+            })
+        }
+
+        fn set(&mut self, key: &str, value: String) {
+            self.comments.retain(|comment| {
+                comment
+                    .split_once('=')
+                    .is_none_or(|(k, _)| !k.eq_ignore_ascii_case(key))
+            });
+            self.comments.push(format!("{key}={value}"));
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            let push_string = |bytes: &mut Vec<u8>, string: &str| {
+                bytes.extend((string.len() as u32).to_le_bytes());
+                bytes.extend(string.as_bytes());
+            };
+            push_string(&mut bytes, &self.vendor);
+            bytes.extend((self.comments.len() as u32).to_le_bytes());
+            for comment in &self.comments {
+                push_string(&mut bytes, comment);
+            }
+            bytes
+        }
+    }
+
+    fn read_u32(data: &mut &[u8]) -> Result<u32> {
+        let mut bytes = [0; 4];
+        data.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_string(data: &mut &[u8]) -> Result<String> {
+        let mut bytes = vec![0; read_u32(data)? as usize];
+        data.read_exact(&mut bytes)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    // Width, height, colour depth and palette size are left as 0 for "unknown"
+    fn picture(artwork: &Artwork) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(FRONT_COVER.to_be_bytes());
+        bytes.extend((artwork.mime_type.len() as u32).to_be_bytes());
+        bytes.extend(artwork.mime_type.as_bytes());
+        bytes.extend(0u32.to_be_bytes());
+        bytes.extend([0; 16]);
+        bytes.extend((artwork.data.len() as u32).to_be_bytes());
+        bytes.extend(&artwork.data);
+        bytes
+    }
+
+    #[cfg(test)]
+    pub(super) fn read_comments(path: &Path) -> Result<Comments> {
+        let blocks = read_blocks(&mut BufReader::new(File::open(path)?))?;
+        blocks
+            .iter()
+            .find(|block| block.kind == VORBIS_COMMENT)
+            .map_or(Ok(Comments::default()), |block| {
+                Comments::parse(&block.data)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flac_tags_fill_in_what_is_missing() {
+        let dir = std::env::temp_dir().join("rusty-piano-tags-test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("01 - track.flac");
+
+        // STREAMINFO, then a Vorbis comment block with only a title, then the "audio"
+        let mut file = b"fLaC".to_vec();
+        file.extend([0, 0, 0, 34]);
+        file.extend([0; 34]);
+        let comment = b"TITLE=Already tagged";
+        let mut comments = Vec::new();
+        comments.extend(0u32.to_le_bytes());
+        comments.extend(1u32.to_le_bytes());
+        comments.extend((comment.len() as u32).to_le_bytes());
+        comments.extend(comment);
+        file.extend([0x80 | 4, 0, 0, comments.len() as u8]);
+        file.extend(&comments);
+        file.extend(b"audio frames");
+        fs::write(&path, &file).unwrap();
+
+        let tags = TrackTags {
+            album: AlbumTags {
+                artist: "Band".to_owned(),
+                album: "Album".to_owned(),
+                year: Some("2020".to_owned()),
+                ..AlbumTags::default()
+            },
+            title: "Track".to_owned(),
+            number: 1,
+            total: 9,
+        };
+        write_tags(&path, &tags, None, TagMode::FillMissing).unwrap();
+
+        let comments = flac::read_comments(&path).unwrap();
+        let value = |key| comments.values(key).collect::<Vec<&str>>();
+        assert_eq!(value("title"), ["Already tagged"]);
+        assert_eq!(value("ARTIST"), ["Band"]);
+        assert_eq!(value("TRACKTOTAL"), ["9"]);
+        assert_eq!(value("DATE"), ["2020"]);
+        assert!(!comments.has("ORGANIZATION"));
+        assert!(fs::read(&path).unwrap().ends_with(b"audio frames"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
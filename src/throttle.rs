@@ -0,0 +1,85 @@
+use chrono::NaiveTime;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Keeps the combined download speed under a limit, so a big download doesn't hog the connection.
+// Downloads take bytes as they receive them and wait whenever the bucket runs dry.
+pub struct RateLimiter {
+    // None means no limit
+    bytes_per_second: Option<u64>,
+    // Bytes that can be taken right now, negative when downloads are ahead of the limit
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.filter(|rate| *rate > 0),
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    pub async fn take(&self, bytes: usize) {
+        let Some(rate) = self.bytes_per_second else {
+            return;
+        };
+        let rate = rate as f64;
+
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (available, refilled_at) = *bucket;
+            let now = Instant::now();
+            // At most a second's worth of bytes can build up while nothing is downloading
+            let available =
+                (available + now.duration_since(refilled_at).as_secs_f64() * rate).min(rate);
+            *bucket = (available - bytes as f64, now);
+            (bytes as f64 - available) / rate
+        };
+
+        if wait > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+// A time of day range (local time) when bulk downloads are allowed to run, e.g. 01:00 to 07:00.
+// The end can be before the start for windows that go past midnight.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DownloadWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl DownloadWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+// Without any windows bulk downloads can run any time
+pub fn in_download_window(windows: &[DownloadWindow], time: NaiveTime) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_can_go_past_midnight() {
+        let windows: Vec<DownloadWindow> =
+            serde_json::from_str(r#"[{ "start": "23:00", "end": "07:00" }]"#).unwrap();
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+
+        assert!(in_download_window(&windows, time(23)));
+        assert!(in_download_window(&windows, time(3)));
+        assert!(!in_download_window(&windows, time(7)));
+        assert!(!in_download_window(&windows, time(12)));
+        assert!(in_download_window(&[], time(12)));
+    }
+}
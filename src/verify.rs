@@ -0,0 +1,237 @@
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use thiserror::Error;
+
+// Sits next to the tracks of every verified album, in the format `sha1sum -c` understands
+pub const MANIFEST_FILE_NAME: &str = "checksums.sha1";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Damage {
+    #[error("file is empty")]
+    Empty,
+    #[error("can't be decoded: {0}")]
+    Undecodable(String),
+    #[error("cut off, {decoded} of {expected} samples are there")]
+    Truncated { decoded: u64, expected: u64 },
+}
+
+// A track that didn't pass verification
+pub struct DamagedTrack {
+    pub album: String,
+    pub track: String,
+    pub file_path: PathBuf,
+    pub damage: Damage,
+}
+
+#[derive(Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub damaged: Vec<DamagedTrack>,
+}
+
+// The checksums of the files in an album directory that were found to be fine
+pub struct Manifest {
+    path: PathBuf,
+    checksums: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn load(album_dir: &Path) -> Result<Self> {
+        let path = album_dir.join(MANIFEST_FILE_NAME);
+        let checksums = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .filter_map(|line| line.split_once("  "))
+                .map(|(checksum, file_name)| (file_name.to_owned(), checksum.to_owned()))
+                .collect(),
+            Err(err) if err.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self { path, checksums })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if self.checksums.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+
+        let contents = self
+            .checksums
+            .iter()
+            .map(|(file_name, checksum)| format!("{checksum}  {file_name}\n"))
+            .collect::<String>();
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+
+    // Checks a file against its checksum, or if it hasn't been verified before (or changed since,
+    // e.g. it was tagged again by another program), that it has audio in it. Files that pass get
+    // their checksum recorded.
+    pub fn verify(&mut self, file_path: &Path) -> Result<Result<(), Damage>> {
+        let file_name = file_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let checksum = sha1_of_file(file_path)?;
+
+        if self.checksums.get(&file_name) == Some(&checksum) {
+            return Ok(Ok(()));
+        }
+
+        let check = check_audio(file_path)?;
+        if check.is_ok() {
+            self.checksums.insert(file_name, checksum);
+        }
+        Ok(check)
+    }
+
+    // Takes over the checksums of files that moved here from another album directory,
+    // some of them under a new name
+    pub fn adopt(&mut self, moved: Manifest, renames: &HashMap<String, String>) {
+        for (file_name, checksum) in moved.checksums {
+            let file_name = renames.get(&file_name).cloned().unwrap_or(file_name);
+            self.checksums.insert(file_name, checksum);
+        }
+    }
+
+    // Forgets a file, so a new download of it gets verified from scratch
+    pub fn remove(&mut self, file_path: &Path) {
+        if let Some(file_name) = file_path.file_name() {
+            self.checksums.remove(&*file_name.to_string_lossy());
+        }
+    }
+}
+
+// Decodes all of the audio, which catches empty files, files that aren't audio at all (e.g. an
+// HTML error page), damaged frames and files that are cut off. Whether a file is cut off is
+// told by the length in its headers (the Xing/LAME frame of an MP3, FLAC's STREAMINFO, ...).
+fn check_audio(file_path: &Path) -> Result<Result<(), Damage>> {
+    if fs::metadata(file_path)?.len() == 0 {
+        return Ok(Err(Damage::Empty));
+    }
+    let undecodable = |err: SymphoniaError| Ok(Err(Damage::Undecodable(err.to_string())));
+
+    let stream = MediaSourceStream::new(Box::new(File::open(file_path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = file_path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut format = match symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(probed) => probed.format,
+        Err(err) => return undecodable(err),
+    };
+    let Some(track) = format.default_track() else {
+        return Ok(Err(Damage::Undecodable("no audio".to_owned())));
+    };
+    let (track_id, expected) = (track.id, track.codec_params.n_frames);
+    let mut decoder = match symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+    {
+        Ok(decoder) => decoder,
+        Err(err) => return undecodable(err),
+    };
+
+    let mut decoded = 0;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // The end of the file
+            Err(SymphoniaError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return undecodable(err),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(audio) => decoded += audio.frames() as u64,
+            Err(err) => return undecodable(err),
+        }
+    }
+
+    Ok(match expected {
+        _ if decoded == 0 => Err(Damage::Undecodable("no audio".to_owned())),
+        Some(expected) if decoded < expected => Err(Damage::Truncated { decoded, expected }),
+        _ => Ok(()),
+    })
+}
+
+fn sha1_of_file(file_path: &Path) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    io::copy(&mut File::open(file_path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::{TagMode, TrackTags, write_tags};
+
+    #[test]
+    fn damaged_files_are_caught() {
+        let album_dir = std::env::temp_dir().join("rusty-piano-verify-test");
+        fs::create_dir_all(&album_dir).unwrap();
+        let empty = album_dir.join("01 - empty.mp3");
+        let not_audio = album_dir.join("02 - not audio.mp3");
+        let changed = album_dir.join("03 - changed.mp3");
+        let cut_off = album_dir.join("04 - cut off.mp3");
+        fs::write(&empty, b"").unwrap();
+        fs::write(&not_audio, b"<html>410 Gone</html>").unwrap();
+        fs::copy("file_example_MP3_2MG.mp3", &changed).unwrap();
+        let contents = fs::read(&changed).unwrap();
+        fs::write(&cut_off, &contents[..contents.len() / 2]).unwrap();
+
+        let mut manifest = Manifest::load(&album_dir).unwrap();
+        assert_eq!(manifest.verify(&empty).unwrap(), Err(Damage::Empty));
+        assert!(matches!(
+            manifest.verify(&not_audio).unwrap(),
+            Err(Damage::Undecodable(_))
+        ));
+        assert!(matches!(
+            manifest.verify(&cut_off).unwrap(),
+            Err(Damage::Truncated { .. })
+        ));
+        assert_eq!(manifest.verify(&changed).unwrap(), Ok(()));
+
+        // Tagged again by another program, which is fine
+        let tags = TrackTags {
+            title: "Retagged".to_owned(),
+            ..TrackTags::default()
+        };
+        write_tags(&changed, &tags, None, TagMode::Overwrite).unwrap();
+        assert_eq!(manifest.verify(&changed).unwrap(), Ok(()));
+        assert_eq!(
+            manifest.checksums["03 - changed.mp3"],
+            sha1_of_file(&changed).unwrap()
+        );
+
+        let mut contents = fs::read(&changed).unwrap();
+        contents.truncate(contents.len() / 2);
+        fs::write(&changed, contents).unwrap();
+        assert!(matches!(
+            manifest.verify(&changed).unwrap(),
+            Err(Damage::Truncated { .. })
+        ));
+
+        fs::remove_dir_all(&album_dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::Result;
+use notify::event::ModifyKind;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+use crate::download_manager::PART_EXTENSION;
+use crate::events::Event;
+
+// Keeps track statuses up to date when files in the library are deleted, copied in or moved
+// around outside the app (see Collection::refresh_paths). Stops watching when it's dropped.
+pub struct LibraryWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl LibraryWatcher {
+    pub fn new(library_root: &Path, sender: Sender<Event>) -> Result<Self> {
+        // There's nothing to watch before the first download otherwise
+        fs::create_dir_all(library_root)?;
+
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(err) => {
+                        let _ = sender.send(Event::Error(
+                            anyhow::Error::new(err).context("Watching the library failed"),
+                        ));
+                        return;
+                    }
+                };
+                let changed = match event.kind {
+                    EventKind::Create(_)
+                    | EventKind::Remove(_)
+                    | EventKind::Modify(ModifyKind::Name(_)) => changed_paths(event.paths),
+                    // Writes to files that are there already (downloads, tagging) don't change what's on disk
+                    _ => Vec::new(),
+                };
+                if !changed.is_empty() {
+                    let _ = sender.send(Event::LibraryChanged(changed));
+                }
+            })?;
+        watcher.watch(library_root, RecursiveMode::Recursive)?;
+
+        Ok(Self { _watcher: watcher })
+    }
+}
+
+// Partial downloads come and go all the time, and the download manager keeps track of them
+fn changed_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    paths
+        .into_iter()
+        .filter(|path| !path.to_string_lossy().ends_with(PART_EXTENSION))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_manager::part_path;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn changes_to_the_library_are_sent() {
+        let library_root = std::env::temp_dir().join("rusty-piano-watcher-test");
+        let _ = fs::remove_dir_all(&library_root);
+        let (sender, receiver) = mpsc::channel();
+        let _watcher = LibraryWatcher::new(&library_root, sender).unwrap();
+
+        let track = library_root.join("01 Song.mp3");
+        fs::write(part_path(&track), b"").unwrap();
+        fs::rename(part_path(&track), &track).unwrap();
+        fs::remove_file(&track).unwrap();
+
+        let mut changed = Vec::new();
+        while let Ok(event) = receiver.recv_timeout(Duration::from_secs(2)) {
+            if let Event::LibraryChanged(paths) = event {
+                changed.extend(paths);
+            }
+        }
+        assert!(changed.contains(&track));
+        assert!(changed.iter().all(|path| *path == track));
+
+        fs::remove_dir_all(&library_root).unwrap();
+    }
+}